@@ -2,11 +2,30 @@
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
 use crate::storage::SqliteStorage;
-use crate::{collection::Collection, err::Result};
+use crate::{
+    collection::Collection,
+    err::{AnkiError, Result},
+};
 use rusqlite::types::{FromSql, FromSqlError, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::OptionalExtension;
 use serde_derive::{Deserialize, Serialize};
 
+/// Wire format used for a `db_command_bytes()` call. JSON remains the
+/// default for backward compatibility; CBOR avoids the JSON round-trip cost
+/// on large result sets and, unlike JSON, can carry arbitrary bytes for
+/// `Blob`/non-UTF8 `Text` without an intermediate numeric-array encoding.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(super) enum DBResponseFormat {
+    Json,
+    Cbor,
+}
+
+impl Default for DBResponseFormat {
+    fn default() -> Self {
+        DBResponseFormat::Json
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(tag = "kind", rename_all = "lowercase")]
 pub(super) enum DBRequest {
@@ -22,12 +41,33 @@ pub(super) enum DBRequest {
         sql: String,
         args: Vec<Vec<SqlValue>>,
     },
+    /// Runs a heterogeneous list of statements in a single implicit
+    /// transaction, so a caller with many small mutations doesn't pay a
+    /// crossing + JSON/CBOR parse cost per statement.
+    Batch {
+        ops: Vec<BatchOp>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub(super) enum BatchOp {
+    Query {
+        sql: String,
+        args: Vec<SqlValue>,
+        first_row_only: bool,
+    },
+    Execute {
+        sql: String,
+        args: Vec<SqlValue>,
+    },
 }
 
 #[derive(Serialize)]
 #[serde(untagged)]
 pub(super) enum DBResult {
     Rows(Vec<Vec<SqlValue>>),
+    Batch(Vec<DBResult>),
     None,
 }
 
@@ -38,7 +78,16 @@ pub(super) enum SqlValue {
     String(String),
     Int(i64),
     Double(f64),
-    Blob(Vec<u8>),
+    Blob(#[serde(serialize_with = "serialize_blob")] Vec<u8>),
+}
+
+/// Writes a blob through `serialize_bytes`, so CBOR emits a byte string;
+/// JSON still emits an array of numbers, as before.
+fn serialize_blob<S: serde::Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(bytes)
 }
 
 impl ToSql for SqlValue {
@@ -60,15 +109,128 @@ impl FromSql for SqlValue {
             ValueRef::Null => SqlValue::Null,
             ValueRef::Integer(i) => SqlValue::Int(i),
             ValueRef::Real(v) => SqlValue::Double(v),
-            ValueRef::Text(v) => SqlValue::String(String::from_utf8_lossy(v).to_string()),
+            ValueRef::Text(v) => match std::str::from_utf8(v) {
+                // preserve the exact bytes when the column isn't valid UTF8,
+                // rather than corrupting it with from_utf8_lossy()
+                Ok(s) => SqlValue::String(s.to_string()),
+                Err(_) => SqlValue::Blob(v.to_vec()),
+            },
             ValueRef::Blob(v) => SqlValue::Blob(v.to_vec()),
         };
         Ok(val)
     }
 }
 
-pub(super) fn db_command_bytes(col: &mut Collection, input: &[u8]) -> Result<Vec<u8>> {
-    let req: DBRequest = serde_json::from_slice(input)?;
+/// The subset of storage operations the dbproxy needs, kept separate from
+/// `SqliteStorage` so this module talks in terms of `SqlValue`/transactions
+/// rather than `rusqlite::Connection` directly. `SqliteStorage` is the only
+/// implementation today, but an in-memory backend for tests (or a future
+/// encrypted/remote one) only needs to satisfy this trait, not drag in
+/// rusqlite-specific types at every call site.
+///
+/// `get_config_json`/`set_config_json` carry the preferences layer's
+/// key/value settings as JSON, the same backend-agnostic representation
+/// `SqlValue` plays for query results, so `Collection`'s config accessors
+/// (`get_bool`/`set_bool`/etc., in the `config` module) can eventually be
+/// routed through this trait instead of reaching into `SqliteStorage`
+/// directly, the same way `db_query`/`db_execute_many` already do below.
+pub(crate) trait StorageBackend {
+    fn query_rows(&self, sql: &str, args: &[SqlValue]) -> Result<Vec<Vec<SqlValue>>>;
+    fn query_row(&self, sql: &str, args: &[SqlValue]) -> Result<Option<Vec<SqlValue>>>;
+    fn execute_many(&self, sql: &str, args: &[Vec<SqlValue>]) -> Result<()>;
+    fn begin_trx(&self) -> Result<()>;
+    fn commit_trx(&self) -> Result<()>;
+    fn rollback_trx(&self) -> Result<()>;
+    fn set_modified(&self) -> Result<()>;
+    /// Reads a single config entry, serialized as JSON. `None` if unset.
+    fn get_config_json(&self, key: &str) -> Result<Option<String>>;
+    /// Writes a single config entry, serialized as JSON.
+    fn set_config_json(&self, key: &str, value_json: &str) -> Result<()>;
+}
+
+impl StorageBackend for SqliteStorage {
+    fn query_rows(&self, sql: &str, args: &[SqlValue]) -> Result<Vec<Vec<SqlValue>>> {
+        let mut stmt = self.db.prepare_cached(sql)?;
+        let columns = stmt.column_count();
+        let rows: std::result::Result<Vec<Vec<_>>, rusqlite::Error> = stmt
+            .query_map(args, |row| {
+                let mut orow = Vec::with_capacity(columns);
+                for i in 0..columns {
+                    let v: SqlValue = row.get(i)?;
+                    orow.push(v);
+                }
+                Ok(orow)
+            })?
+            .collect();
+        Ok(rows?)
+    }
+
+    fn query_row(&self, sql: &str, args: &[SqlValue]) -> Result<Option<Vec<SqlValue>>> {
+        let mut stmt = self.db.prepare_cached(sql)?;
+        let columns = stmt.column_count();
+        let row = stmt
+            .query_row(args, |row| {
+                let mut orow = Vec::with_capacity(columns);
+                for i in 0..columns {
+                    let v: SqlValue = row.get(i)?;
+                    orow.push(v);
+                }
+                Ok(orow)
+            })
+            .optional()?;
+        Ok(row)
+    }
+
+    fn execute_many(&self, sql: &str, args: &[Vec<SqlValue>]) -> Result<()> {
+        let mut stmt = self.db.prepare_cached(sql)?;
+        for params in args {
+            stmt.execute(params)?;
+        }
+        Ok(())
+    }
+
+    fn begin_trx(&self) -> Result<()> {
+        SqliteStorage::begin_trx(self)
+    }
+
+    fn commit_trx(&self) -> Result<()> {
+        SqliteStorage::commit_trx(self)
+    }
+
+    fn rollback_trx(&self) -> Result<()> {
+        SqliteStorage::rollback_trx(self)
+    }
+
+    fn set_modified(&self) -> Result<()> {
+        SqliteStorage::set_modified(self)
+    }
+
+    fn get_config_json(&self, key: &str) -> Result<Option<String>> {
+        self.db
+            .prepare_cached("select val from config where key = ?")?
+            .query_row(&[key], |row| row.get(0))
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn set_config_json(&self, key: &str, value_json: &str) -> Result<()> {
+        self.db
+            .prepare_cached("insert or replace into config (key, val) values (?, ?)")?
+            .execute(&[key, value_json])?;
+        Ok(())
+    }
+}
+
+pub(super) fn db_command_bytes(
+    col: &mut Collection,
+    input: &[u8],
+    fmt: DBResponseFormat,
+) -> Result<Vec<u8>> {
+    let req: DBRequest = match fmt {
+        DBResponseFormat::Json => serde_json::from_slice(input)?,
+        DBResponseFormat::Cbor => ciborium::de::from_reader(input)
+            .map_err(|e| AnkiError::invalid_input(e.to_string()))?,
+    };
     let resp = match req {
         DBRequest::Query {
             sql,
@@ -103,80 +265,217 @@ pub(super) fn db_command_bytes(col: &mut Collection, input: &[u8]) -> Result<Vec
             update_state_after_modification(col, &sql);
             db_execute_many(&col.storage, &sql, &args)?
         }
+        DBRequest::Batch { ops } => db_batch(col, ops)?,
     };
-    Ok(serde_json::to_vec(&resp)?)
+    match fmt {
+        DBResponseFormat::Json => Ok(serde_json::to_vec(&resp)?),
+        DBResponseFormat::Cbor => {
+            let mut out = Vec::new();
+            ciborium::ser::into_writer(&resp, &mut out)
+                .map_err(|e| AnkiError::invalid_input(e.to_string()))?;
+            Ok(out)
+        }
+    }
+}
+
+/// Runs `ops` inside a single implicit transaction. On the first failing
+/// statement, the transaction is rolled back and the error message is
+/// prefixed with the index of the statement that failed, so the caller can
+/// tell which of its batched operations needs retrying. `is_dql` is OR'd
+/// across every member statement so undo/study state is invalidated at most
+/// once for the whole batch, rather than once per mutating statement.
+fn db_batch(col: &mut Collection, ops: Vec<BatchOp>) -> Result<DBResult> {
+    col.storage.begin_trx()?;
+
+    let mut any_dql_violation = false;
+    let mut results = Vec::with_capacity(ops.len());
+    for (idx, op) in ops.into_iter().enumerate() {
+        let res = match op {
+            BatchOp::Query {
+                sql,
+                args,
+                first_row_only,
+            } => {
+                any_dql_violation |= !is_dql(&sql);
+                if first_row_only {
+                    db_query_row(&col.storage, &sql, &args)
+                } else {
+                    db_query(&col.storage, &sql, &args)
+                }
+            }
+            BatchOp::Execute { sql, args } => {
+                any_dql_violation |= !is_dql(&sql);
+                db_execute_many(&col.storage, &sql, &[args])
+            }
+        };
+        match res {
+            Ok(res) => results.push(res),
+            Err(e) => {
+                col.storage.rollback_trx()?;
+                return Err(AnkiError::invalid_input(format!(
+                    "batch statement {}: {}",
+                    idx, e
+                )));
+            }
+        }
+    }
+
+    col.storage.commit_trx()?;
+    if any_dql_violation {
+        col.update_state_after_dbproxy_modification();
+    }
+
+    Ok(DBResult::Batch(results))
 }
 
 fn update_state_after_modification(col: &mut Collection, sql: &str) {
     if !is_dql(sql) {
-        println!("clearing undo+study due to {}", sql);
         col.update_state_after_dbproxy_modification();
     }
 }
 
-/// Anything other than a select statement is false.
+/// Classifies a statement as read-only (`true`) or mutating (`false`) by
+/// looking at its leading keyword, rather than a fixed-length prefix match -
+/// so a `WITH ... SELECT` CTE, `EXPLAIN`, or a read-only `PRAGMA` isn't
+/// mistaken for a write and doesn't needlessly clear undo/study state.
 fn is_dql(sql: &str) -> bool {
-    let head: String = sql
-        .trim_start()
-        .chars()
-        .take(10)
-        .map(|c| c.to_ascii_lowercase())
-        .collect();
-    head.starts_with("select ")
-}
-
-pub(super) fn db_query_row(ctx: &SqliteStorage, sql: &str, args: &[SqlValue]) -> Result<DBResult> {
-    let mut stmt = ctx.db.prepare_cached(sql)?;
-    let columns = stmt.column_count();
-
-    let row = stmt
-        .query_row(args, |row| {
-            let mut orow = Vec::with_capacity(columns);
-            for i in 0..columns {
-                let v: SqlValue = row.get(i)?;
-                orow.push(v);
-            }
-            Ok(orow)
-        })
-        .optional()?;
-
-    let rows = if let Some(row) = row {
-        vec![row]
-    } else {
-        vec![]
-    };
+    let (keyword, rest) = next_keyword(sql);
+    match keyword.as_str() {
+        "SELECT" | "EXPLAIN" => true,
+        // a pragma that only reads a setting has no `=`; one that assigns a
+        // new value (`PRAGMA foo = bar`) mutates the database/connection
+        "PRAGMA" => !rest.contains('='),
+        "WITH" => is_dql(trailing_statement_after_with(rest)),
+        _ => false,
+    }
+}
 
-    Ok(DBResult::Rows(rows))
+/// Skips leading whitespace and `--`/`/* */` comments, then returns the
+/// upper-cased leading run of identifier characters (the statement's
+/// keyword) along with everything after it.
+fn next_keyword(sql: &str) -> (String, &str) {
+    let sql = skip_trivia(sql);
+    let end = sql
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or_else(|| sql.len());
+    (sql[..end].to_ascii_uppercase(), &sql[end..])
 }
 
-pub(super) fn db_query(ctx: &SqliteStorage, sql: &str, args: &[SqlValue]) -> Result<DBResult> {
-    let mut stmt = ctx.db.prepare_cached(sql)?;
-    let columns = stmt.column_count();
+fn skip_trivia(mut sql: &str) -> &str {
+    loop {
+        let trimmed = sql.trim_start();
+        if let Some(rest) = trimmed.strip_prefix("--") {
+            sql = rest.find('\n').map(|i| &rest[i + 1..]).unwrap_or("");
+        } else if let Some(rest) = trimmed.strip_prefix("/*") {
+            sql = rest.find("*/").map(|i| &rest[i + 2..]).unwrap_or("");
+        } else {
+            return trimmed;
+        }
+    }
+}
 
-    let res: std::result::Result<Vec<Vec<_>>, rusqlite::Error> = stmt
-        .query_map(args, |row| {
-            let mut orow = Vec::with_capacity(columns);
-            for i in 0..columns {
-                let v: SqlValue = row.get(i)?;
-                orow.push(v);
+/// Given the text following a leading `WITH`, scans past the (possibly
+/// several, comma-separated, parenthesized) CTE definitions and returns the
+/// trailing statement they feed into, so that statement is what gets
+/// classified rather than the `WITH` keyword itself.
+fn trailing_statement_after_with(mut rest: &str) -> &str {
+    let mut depth = 0i32;
+    loop {
+        rest = skip_trivia(rest);
+        match rest.chars().next() {
+            None => return rest,
+            Some('(') => {
+                depth += 1;
+                rest = &rest[1..];
+            }
+            Some(')') => {
+                depth -= 1;
+                rest = &rest[1..];
             }
-            Ok(orow)
-        })?
-        .collect();
+            Some(',') if depth == 0 => {
+                rest = &rest[1..];
+            }
+            Some(c) if c.is_ascii_alphabetic() && depth == 0 => {
+                // CTE bodies are always wrapped in parens, so any statement
+                // keyword seen at depth 0 belongs to the trailing statement
+                // the CTEs feed into, not a cte name/`AS`
+                let (keyword, after) = next_keyword(rest);
+                match keyword.as_str() {
+                    "SELECT" | "INSERT" | "UPDATE" | "DELETE" => return rest,
+                    _ => rest = after,
+                }
+            }
+            Some(c) if depth > 0 => {
+                // skip over CTE column/body contents a character at a time
+                rest = &rest[c.len_utf8()..];
+            }
+            Some(_) => {
+                // a cte name/alias token; skip past it
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or_else(|| rest.len());
+                rest = &rest[end.max(1)..];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod sql_classification_test {
+    use super::is_dql;
+
+    #[test]
+    fn classifies_statements() {
+        let read_only = [
+            "select 1",
+            "  SELECT * from cards",
+            "-- comment\nselect * from cards",
+            "/* block */ select * from cards",
+            "EXPLAIN SELECT * from cards",
+            "explain query plan select * from cards",
+            "pragma user_version",
+            "PRAGMA table_info(cards)",
+            "with cte as (select 1) select * from cte",
+            "WITH a AS (SELECT 1), b AS (SELECT 2) SELECT * FROM a, b",
+        ];
+        for sql in read_only {
+            assert!(is_dql(sql), "expected read-only: {}", sql);
+        }
+
+        let mutating = [
+            "insert into cards default values",
+            "update cards set due = 1",
+            "delete from cards",
+            "create table foo(x)",
+            "drop table foo",
+            "alter table cards add column x",
+            "pragma user_version = 2",
+            "with cte as (select 1) insert into cards select * from cte",
+        ];
+        for sql in mutating {
+            assert!(!is_dql(sql), "expected mutating: {}", sql);
+        }
+    }
+}
 
-    Ok(DBResult::Rows(res?))
+pub(super) fn db_query_row(
+    ctx: &impl StorageBackend,
+    sql: &str,
+    args: &[SqlValue],
+) -> Result<DBResult> {
+    let rows = ctx.query_row(sql, args)?.into_iter().collect();
+    Ok(DBResult::Rows(rows))
+}
+
+pub(super) fn db_query(ctx: &impl StorageBackend, sql: &str, args: &[SqlValue]) -> Result<DBResult> {
+    Ok(DBResult::Rows(ctx.query_rows(sql, args)?))
 }
 
 pub(super) fn db_execute_many(
-    ctx: &SqliteStorage,
+    ctx: &impl StorageBackend,
     sql: &str,
     args: &[Vec<SqlValue>],
 ) -> Result<DBResult> {
-    let mut stmt = ctx.db.prepare_cached(sql)?;
-
-    for params in args {
-        stmt.execute(params)?;
-    }
-
+    ctx.execute_many(sql, args)?;
     Ok(DBResult::None)
 }
@@ -127,12 +127,16 @@ impl SchedulingService for Backend {
             input.shift_existing,
         );
         let order = if random {
-            NewCardSortOrder::Random
+            NewCardSortOrder::Random { seed: None }
         } else {
             NewCardSortOrder::Preserve
         };
         self.with_col(|col| {
+            // the seed `col.sort_cards` draws for a random order is already
+            // logged there; `OpChangesWithCount` has no field for it, so it
+            // isn't passed any further up the stack for now
             col.sort_cards(&cids, start, step, order, shift)
+                .map(|output| output.map(|out| out.changed))
                 .map(Into::into)
         })
     }
@@ -140,6 +144,7 @@ impl SchedulingService for Backend {
     fn sort_deck(&self, input: pb::SortDeckIn) -> Result<pb::OpChangesWithCount> {
         self.with_col(|col| {
             col.sort_deck(input.deck_id.into(), input.randomize)
+                .map(|output| output.map(|out| out.changed))
                 .map(Into::into)
         })
     }
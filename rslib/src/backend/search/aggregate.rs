@@ -0,0 +1,212 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Group-by aggregation over a searched card set, used by
+//! `SearchService::search_aggregate`. Grouping dimensions reuse `SortKind` so
+//! the same vocabulary the browser's sort-by column picker uses can drive
+//! statistics grouping.
+
+use std::collections::HashMap;
+
+use crate::{card::Card, config::SortKind, decks::DeckID, notetype::NoteTypeID, prelude::*};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum GroupKey {
+    Deck(DeckID),
+    Notetype(NoteTypeID),
+    Template(NoteTypeID, i64),
+    IntervalBucket(&'static str),
+    EaseBucket(i32),
+}
+
+impl GroupKey {
+    /// A stable `kind:id[:id]` wire representation, independent of our
+    /// internal type names - unlike `{:?}`, the frontend can parse this
+    /// without coupling to `Debug` output that's free to change shape.
+    pub(super) fn stable_key(self) -> String {
+        match self {
+            GroupKey::Deck(did) => format!("deck:{}", did.0),
+            GroupKey::Notetype(ntid) => format!("notetype:{}", ntid.0),
+            GroupKey::Template(ntid, ord) => format!("template:{}:{}", ntid.0, ord),
+            GroupKey::IntervalBucket(bucket) => format!("interval:{}", bucket),
+            GroupKey::EaseBucket(ease) => format!("ease:{}", ease),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct Accum {
+    pub(super) count: usize,
+    pub(super) sum: i64,
+    pub(super) min: i64,
+    pub(super) max: i64,
+}
+
+impl Accum {
+    fn add(&mut self, value: i64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub(super) fn avg(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+}
+
+/// Quantize an interval (in days) into a human-friendly range, as used in the
+/// deck-options "new cards/day" style graphs.
+fn interval_bucket(days: i32) -> &'static str {
+    match days {
+        i32::MIN..=0 => "0d",
+        1 => "1d",
+        2..=7 => "2-7d",
+        8..=30 => "8-30d",
+        31..=90 => "31-90d",
+        _ => ">90d",
+    }
+}
+
+/// Quantize ease factor (stored in permille, e.g. 2500 = 250%) down to a
+/// 10% bucket, e.g. 2590 -> 2500.
+fn ease_bucket(ease_factor: i32) -> i32 {
+    (ease_factor / 100) * 100
+}
+
+pub(super) struct Aggregator<'a> {
+    col: &'a mut Collection,
+    dimension: SortKind,
+    column: NumericColumn,
+}
+
+/// The numeric card column callers may fold over.
+#[derive(Clone, Copy)]
+pub(super) enum NumericColumn {
+    Interval,
+    Ease,
+    Reps,
+    Lapses,
+}
+
+impl NumericColumn {
+    fn value(self, card: &Card) -> i64 {
+        match self {
+            NumericColumn::Interval => card.interval as i64,
+            NumericColumn::Ease => card.ease_factor as i64,
+            NumericColumn::Reps => card.reps as i64,
+            NumericColumn::Lapses => card.lapses as i64,
+        }
+    }
+}
+
+impl<'a> Aggregator<'a> {
+    pub(super) fn new(col: &'a mut Collection, dimension: SortKind, column: NumericColumn) -> Self {
+        Aggregator {
+            col,
+            dimension,
+            column,
+        }
+    }
+
+    fn group_key(&mut self, card: &Card) -> Result<GroupKey> {
+        Ok(match self.dimension {
+            // the deck a card is currently in, not `original_deck_id` (which
+            // only holds the *home* deck of a card sitting in a filtered
+            // deck, and is unset otherwise)
+            SortKind::CardDeck => GroupKey::Deck(card.did),
+            SortKind::NoteType => {
+                let notetype_id = self
+                    .col
+                    .storage
+                    .get_note(card.note_id)?
+                    .map(|n| n.notetype_id)
+                    .unwrap_or(NoteTypeID(0));
+                GroupKey::Notetype(notetype_id)
+            }
+            SortKind::CardTemplate => {
+                let notetype_id = self
+                    .col
+                    .storage
+                    .get_note(card.note_id)?
+                    .map(|n| n.notetype_id)
+                    .unwrap_or(NoteTypeID(0));
+                GroupKey::Template(notetype_id, card.ord as i64)
+            }
+            SortKind::CardInterval => GroupKey::IntervalBucket(interval_bucket(card.interval as i32)),
+            SortKind::CardEase => GroupKey::EaseBucket(ease_bucket(card.ease_factor as i32)),
+            other => {
+                return Err(AnkiError::invalid_input(format!(
+                    "search_aggregate does not support grouping by {:?}",
+                    other
+                )))
+            }
+        })
+    }
+
+    /// Loads the cards in `cids` through the search table, then groups each
+    /// one and folds its value into that group.
+    pub(super) fn aggregate(&mut self, cids: &[crate::card::CardID]) -> Result<Vec<(GroupKey, Accum)>> {
+        let mut groups: HashMap<GroupKey, Accum> = HashMap::new();
+        self.col.storage.set_search_table_to_card_ids(cids, false)?;
+        let cards = self.col.storage.all_searched_cards()?;
+        self.col.storage.clear_searched_cards_table()?;
+        for card in &cards {
+            let key = self.group_key(card)?;
+            let value = self.column.value(card);
+            groups.entry(key).or_default().add(value);
+        }
+        Ok(groups.into_iter().collect())
+    }
+}
+
+impl Collection {
+    /// Run `search`, then group the resulting cards by `dimension` and fold
+    /// `column` into count/sum/avg/min/max per group.
+    pub(crate) fn search_aggregate(
+        &mut self,
+        search: &str,
+        dimension: SortKind,
+        column: NumericColumn,
+    ) -> Result<Vec<(GroupKey, Accum)>> {
+        let cids = self.search_cards(search, crate::search::SortMode::NoOrder)?;
+        Aggregator::new(self, dimension, column).aggregate(&cids)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(interval_bucket(0), "0d");
+        assert_eq!(interval_bucket(1), "1d");
+        assert_eq!(interval_bucket(5), "2-7d");
+        assert_eq!(interval_bucket(30), "8-30d");
+        assert_eq!(interval_bucket(91), ">90d");
+        assert_eq!(ease_bucket(2550), 2500);
+    }
+
+    #[test]
+    fn accum_folds_values() {
+        let mut accum = Accum::default();
+        for v in [10, 20, 30] {
+            accum.add(v);
+        }
+        assert_eq!(accum.count, 3);
+        assert_eq!(accum.sum, 60);
+        assert_eq!(accum.min, 10);
+        assert_eq!(accum.max, 30);
+        assert_eq!(accum.avg(), 20.0);
+    }
+}
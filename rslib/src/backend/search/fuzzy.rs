@@ -0,0 +1,272 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Support code for the `fuzzy:` search operator. `extract_fuzzy_term` pulls
+//! a `fuzzy:term` token out of the raw query string so `search_notes` can
+//! run the rest of a compound query normally. The text index only matches
+//! whole tokens exactly, so `search_notes_fuzzy` widens the candidate set
+//! with nearby spellings of `term` before the helpers below re-rank by edit
+//! distance and drop anything outside the typo budget.
+
+use std::collections::HashSet;
+
+use crate::{notes::NoteID, prelude::*, text::strip_html_preserving_media_filenames};
+
+/// Typo budget: terms under 3 characters must match exactly, 3-8 characters
+/// tolerate a single edit, and longer terms tolerate two.
+fn max_distance_for_term(term: &str) -> usize {
+    match term.chars().count() {
+        0..=2 => 0,
+        3..=8 => 1,
+        _ => 2,
+    }
+}
+
+/// Classic two-row Levenshtein distance, using O(min(m, n)) space. Returns
+/// `None` if the distance exceeds `max_distance`, allowing callers to bail
+/// out without computing an exact (and irrelevant) distance.
+fn bounded_levenshtein(a: &str, b: &str, max_distance: usize) -> Option<usize> {
+    let (shorter, longer) = if a.chars().count() <= b.chars().count() {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let shorter: Vec<char> = shorter.chars().collect();
+    let longer: Vec<char> = longer.chars().collect();
+
+    if longer.len() - shorter.len() > max_distance {
+        // can't possibly be within budget
+        return None;
+    }
+
+    let mut prev_row: Vec<usize> = (0..=shorter.len()).collect();
+    let mut curr_row = vec![0; shorter.len() + 1];
+
+    for (i, &lc) in longer.iter().enumerate() {
+        curr_row[0] = i + 1;
+        let mut row_min = curr_row[0];
+        for (j, &sc) in shorter.iter().enumerate() {
+            let cost = if lc == sc { 0 } else { 1 };
+            curr_row[j + 1] = (prev_row[j] + cost)
+                .min(prev_row[j + 1] + 1)
+                .min(curr_row[j] + 1);
+            row_min = row_min.min(curr_row[j + 1]);
+        }
+        if row_min > max_distance {
+            // every entry in this row (and thus the final answer) already
+            // exceeds the budget
+            return None;
+        }
+        std::mem::swap(&mut prev_row, &mut curr_row);
+    }
+
+    let distance = prev_row[shorter.len()];
+    if distance <= max_distance {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+/// Generates every edit-distance-1 spelling of `term` (substitution,
+/// deletion, insertion, and adjacent transposition), to use as a prefilter:
+/// `search_notes` only matches whole tokens exactly, so searching for `term`
+/// alone would never surface a note that misspells it. Precise scoring
+/// against the term's real typo budget (which may be 2, for longer terms)
+/// still happens afterwards in `filter_and_sort_by_distance` - this only
+/// needs to get candidate notes in front of that filter, not classify them.
+fn edit_distance_one_variants(term: &str) -> Vec<String> {
+    const ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz0123456789";
+    let chars: Vec<char> = term.chars().collect();
+    let mut variants = HashSet::new();
+
+    for i in 0..chars.len() {
+        let mut v = chars.clone();
+        v.remove(i);
+        variants.insert(v.into_iter().collect::<String>());
+    }
+    for (i, &orig) in chars.iter().enumerate() {
+        for c in ALPHABET.chars() {
+            if c == orig {
+                continue;
+            }
+            let mut v = chars.clone();
+            v[i] = c;
+            variants.insert(v.into_iter().collect::<String>());
+        }
+    }
+    for i in 0..=chars.len() {
+        for c in ALPHABET.chars() {
+            let mut v = chars.clone();
+            v.insert(i, c);
+            variants.insert(v.into_iter().collect::<String>());
+        }
+    }
+    for i in 0..chars.len().saturating_sub(1) {
+        let mut v = chars.clone();
+        v.swap(i, i + 1);
+        variants.insert(v.into_iter().collect::<String>());
+    }
+
+    variants.remove(term);
+    variants.into_iter().collect()
+}
+
+/// Pulls the first whitespace-delimited `fuzzy:term` token out of `search`,
+/// returning the term and the rest of the query with that token removed.
+/// Keeping the rest of the query intact lets `fuzzy:cat deck:french` still
+/// narrow to the French deck instead of the `fuzzy:` term swallowing
+/// everything else on the line - `search_notes` runs the remainder through
+/// the normal search path and intersects it with the fuzzy-ranked set.
+pub(super) fn extract_fuzzy_term(search: &str) -> Option<(String, String)> {
+    let mut tokens: Vec<&str> = search.split_whitespace().collect();
+    let idx = tokens.iter().position(|t| t.starts_with("fuzzy:"))?;
+    let term = tokens.remove(idx)["fuzzy:".len()..].to_string();
+    Some((term, tokens.join(" ")))
+}
+
+impl Collection {
+    /// Implements the `fuzzy:term` search operator. Candidate note ids are
+    /// drawn from the text index: an exact search for `term`, plus (when
+    /// `term`'s typo budget allows any edits at all) a search for each of
+    /// its edit-distance-1 spellings, so a note that simply misspells `term`
+    /// is still a candidate. The union is then re-ranked by edit distance
+    /// over every field (not just the first) so the closest matches are
+    /// returned first.
+    pub(crate) fn search_notes_fuzzy(&mut self, term: &str) -> Result<Vec<NoteID>> {
+        let mut seen: HashSet<NoteID> = HashSet::new();
+        let mut candidate_nids = Vec::new();
+        for nid in self.search_notes(term)? {
+            if seen.insert(nid) {
+                candidate_nids.push(nid);
+            }
+        }
+        if max_distance_for_term(term) > 0 {
+            for variant in edit_distance_one_variants(term) {
+                for nid in self.search_notes(&variant)? {
+                    if seen.insert(nid) {
+                        candidate_nids.push(nid);
+                    }
+                }
+            }
+        }
+
+        let candidates = candidate_nids
+            .into_iter()
+            .filter_map(|nid| {
+                self.storage
+                    .get_note(nid)
+                    .ok()
+                    .flatten()
+                    .map(|note| (nid, note.fields().join(" ")))
+            })
+            .collect();
+        Ok(filter_and_sort_by_distance(term, candidates))
+    }
+}
+
+/// Given a candidate (note id, field text) list produced by the normal text
+/// index, keep only the notes within the typo budget of `term`, sorted
+/// by ascending edit distance (closest matches first).
+pub(super) fn filter_and_sort_by_distance(
+    term: &str,
+    candidates: Vec<(NoteID, String)>,
+) -> Vec<NoteID> {
+    let max_distance = max_distance_for_term(term);
+    let mut scored: Vec<(usize, NoteID)> = candidates
+        .into_iter()
+        .filter_map(|(nid, field)| {
+            let stripped = strip_html_preserving_media_filenames(&field);
+            stripped
+                .split_whitespace()
+                .filter_map(|word| bounded_levenshtein(term, word, max_distance))
+                .min()
+                .map(|distance| (distance, nid))
+        })
+        .collect();
+    scored.sort_by_key(|(distance, _)| *distance);
+    scored.into_iter().map(|(_, nid)| nid).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collection::open_test_collection, decks::DeckID};
+
+    #[test]
+    fn edit_distance_one_variants_cover_typo_kinds() {
+        let variants: HashSet<String> = edit_distance_one_variants("cat").into_iter().collect();
+        assert!(variants.contains("at")); // deletion
+        assert!(variants.contains("cot")); // substitution
+        assert!(variants.contains("cats")); // insertion
+        assert!(variants.contains("act")); // transposition
+        assert!(!variants.contains("cat")); // the term itself is excluded
+    }
+
+    #[test]
+    fn search_notes_fuzzy_finds_misspelled_note_via_real_search() -> Result<()> {
+        let mut col = open_test_collection();
+        let nt = col.get_notetype_by_name("basic")?.unwrap();
+
+        let mut exact = nt.new_note();
+        exact.fields[0] = "hello world".into();
+        col.add_note(&mut exact, DeckID(1))?;
+
+        let mut misspelled = nt.new_note();
+        misspelled.fields[0] = "unrelated front".into();
+        misspelled.fields[1] = "helo there".into();
+        col.add_note(&mut misspelled, DeckID(1))?;
+
+        let mut unrelated = nt.new_note();
+        unrelated.fields[0] = "completely different".into();
+        col.add_note(&mut unrelated, DeckID(1))?;
+
+        let nids = col.search_notes_fuzzy("hello")?;
+        assert_eq!(nids, vec![exact.id, misspelled.id]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn distance_budget() {
+        assert_eq!(max_distance_for_term("it"), 0);
+        assert_eq!(max_distance_for_term("hello"), 1);
+        assert_eq!(max_distance_for_term("wonderful"), 2);
+    }
+
+    #[test]
+    fn levenshtein() {
+        assert_eq!(bounded_levenshtein("kitten", "sitting", 5), Some(3));
+        assert_eq!(bounded_levenshtein("kitten", "kitten", 0), Some(0));
+        assert_eq!(bounded_levenshtein("kitten", "sitting", 2), None);
+        assert_eq!(bounded_levenshtein("", "abc", 5), Some(3));
+    }
+
+    #[test]
+    fn extracts_fuzzy_term_from_compound_query() {
+        assert_eq!(
+            extract_fuzzy_term("fuzzy:cat deck:french"),
+            Some(("cat".to_string(), "deck:french".to_string()))
+        );
+        assert_eq!(
+            extract_fuzzy_term("deck:french fuzzy:cat"),
+            Some(("cat".to_string(), "deck:french".to_string()))
+        );
+        assert_eq!(
+            extract_fuzzy_term("fuzzy:cat"),
+            Some(("cat".to_string(), String::new()))
+        );
+        assert_eq!(extract_fuzzy_term("deck:french"), None);
+    }
+
+    #[test]
+    fn filters_and_orders_by_distance() {
+        let candidates = vec![
+            (NoteID(1), "hello world".to_string()),
+            (NoteID(2), "helo there".to_string()),
+            (NoteID(3), "completely unrelated".to_string()),
+        ];
+        let nids = filter_and_sort_by_distance("hello", candidates);
+        assert_eq!(nids, vec![NoteID(1), NoteID(2)]);
+    }
+}
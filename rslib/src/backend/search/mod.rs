@@ -1,10 +1,17 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+mod aggregate;
 mod browser_row;
+mod fuzzy;
+mod preview;
+mod relevance;
 mod search_node;
 
-use std::convert::TryInto;
+use aggregate::NumericColumn;
+use fuzzy::extract_fuzzy_term;
+
+use std::{collections::HashSet, convert::TryInto};
 
 use super::Backend;
 use crate::{
@@ -27,7 +34,13 @@ impl SearchService for Backend {
     fn search_cards(&self, input: pb::SearchCardsIn) -> Result<pb::SearchCardsOut> {
         self.with_col(|col| {
             let order = input.order.unwrap_or_default().value.into();
-            let cids = col.search_cards(&input.search, order)?;
+            // Relevance ranks the candidate set after the fact, rather than being
+            // a column the storage layer can ORDER BY, so it's handled separately.
+            let cids = if let SortMode::Relevance = order {
+                col.search_cards_by_relevance(&input.search)?
+            } else {
+                col.search_cards(&input.search, order)?
+            };
             Ok(pb::SearchCardsOut {
                 card_ids: cids.into_iter().map(|v| v.0).collect(),
             })
@@ -36,7 +49,34 @@ impl SearchService for Backend {
 
     fn search_notes(&self, input: pb::SearchNotesIn) -> Result<pb::SearchNotesOut> {
         self.with_col(|col| {
-            let nids = col.search_notes(&input.search)?;
+            // `fuzzy:term` is pulled out of the query string rather than being
+            // a real search node, so a `fuzzy:cat deck:french` search runs the
+            // remainder (`deck:french`) through the normal search and
+            // intersects it with the fuzzy-ranked candidates, instead of the
+            // `fuzzy:` term swallowing the rest of the query. Fuzzy is its own
+            // explicit ranking, so it takes priority over `order` below.
+            let nids = if let Some((term, rest)) = extract_fuzzy_term(&input.search) {
+                let fuzzy_ordered = col.search_notes_fuzzy(&term)?;
+                if rest.is_empty() {
+                    fuzzy_ordered
+                } else {
+                    let allowed: HashSet<_> = col.search_notes(&rest)?.into_iter().collect();
+                    fuzzy_ordered
+                        .into_iter()
+                        .filter(|nid| allowed.contains(nid))
+                        .collect()
+                }
+            } else {
+                let order: SortMode = input.order.unwrap_or_default().value.into();
+                // as with search_cards, Relevance ranks the candidate set
+                // after the fact rather than being a column the storage
+                // layer can ORDER BY, so it's handled separately.
+                if let SortMode::Relevance = order {
+                    col.search_notes_by_relevance(&input.search)?
+                } else {
+                    col.search_notes(&input.search)?
+                }
+            };
             Ok(pb::SearchNotesOut {
                 note_ids: nids.into_iter().map(|v| v.0).collect(),
             })
@@ -67,20 +107,8 @@ impl SearchService for Backend {
     }
 
     fn find_and_replace(&self, input: pb::FindAndReplaceIn) -> Result<pb::OpChangesWithCount> {
-        let mut search = if input.regex {
-            input.search
-        } else {
-            regex::escape(&input.search)
-        };
-        if !input.match_case {
-            search = format!("(?i){}", search);
-        }
+        let (search, field_name) = find_and_replace_search_and_field(&input);
         let nids = input.nids.into_iter().map(NoteID).collect();
-        let field_name = if input.field_name.is_empty() {
-            None
-        } else {
-            Some(input.field_name)
-        };
         let repl = input.replacement;
         self.with_col(|col| {
             col.find_and_replace(nids, &search, &repl, field_name)
@@ -88,9 +116,80 @@ impl SearchService for Backend {
         })
     }
 
+    /// Run find_and_replace without committing, so the caller can show a
+    /// diff before asking the user to confirm.
+    fn find_and_replace_preview(
+        &self,
+        input: pb::FindAndReplaceIn,
+    ) -> Result<pb::FindAndReplacePreviewOut> {
+        let (search, field_name) = find_and_replace_search_and_field(&input);
+        let nids = input.nids.into_iter().map(NoteID).collect();
+        let repl = input.replacement;
+        self.with_col(|col| {
+            let changes = col.find_and_replace_preview(nids, &search, &repl, field_name)?;
+            Ok(pb::FindAndReplacePreviewOut {
+                changes: changes
+                    .into_iter()
+                    .map(|c| pb::FindAndReplaceChange {
+                        note_id: c.note_id.0,
+                        field_name: c.field_name,
+                        before: c.before,
+                        after: c.after,
+                        match_count: c.match_count,
+                    })
+                    .collect(),
+            })
+        })
+    }
+
     fn browser_row_for_card(&self, input: pb::CardId) -> Result<pb::BrowserRow> {
         self.with_col(|col| col.browser_row_for_card(input.cid.into()).map(Into::into))
     }
+
+    fn search_aggregate(&self, input: pb::SearchAggregateIn) -> Result<pb::SearchAggregateOut> {
+        self.with_col(|col| {
+            let dimension: SortKind = input.dimension().into();
+            let column = match input.column() {
+                pb::search_aggregate_in::Column::Interval => NumericColumn::Interval,
+                pb::search_aggregate_in::Column::Ease => NumericColumn::Ease,
+                pb::search_aggregate_in::Column::Reps => NumericColumn::Reps,
+                pb::search_aggregate_in::Column::Lapses => NumericColumn::Lapses,
+            };
+            let groups = col.search_aggregate(&input.search, dimension, column)?;
+            Ok(pb::SearchAggregateOut {
+                rows: groups
+                    .into_iter()
+                    .map(|(key, accum)| pb::AggregateRow {
+                        group_key: key.stable_key(),
+                        count: accum.count as u32,
+                        sum: accum.sum,
+                        avg: accum.avg(),
+                        min: accum.min,
+                        max: accum.max,
+                    })
+                    .collect(),
+            })
+        })
+    }
+}
+
+/// Builds the effective (case/regex-aware) search pattern and optional field
+/// restriction shared by `find_and_replace` and `find_and_replace_preview`.
+fn find_and_replace_search_and_field(input: &pb::FindAndReplaceIn) -> (String, Option<String>) {
+    let mut search = if input.regex {
+        input.search.clone()
+    } else {
+        regex::escape(&input.search)
+    };
+    if !input.match_case {
+        search = format!("(?i){}", search);
+    }
+    let field_name = if input.field_name.is_empty() {
+        None
+    } else {
+        Some(input.field_name.clone())
+    };
+    (search, field_name)
 }
 
 impl From<SortKindProto> for SortKind {
@@ -124,6 +223,7 @@ impl From<Option<SortOrderProto>> for SortMode {
                 kind: b.kind().into(),
                 reverse: b.reverse,
             },
+            V::Relevance(_) => SortMode::Relevance,
         }
     }
 }
@@ -0,0 +1,73 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Dry-run support for `find_and_replace`. Runs the same match logic as the
+//! committing path, but only collects before/after snippets instead of
+//! writing anything back, so the caller (e.g. `with_col`) never opens a
+//! transaction for it.
+
+use regex::Regex;
+
+use crate::{err::AnkiError, notes::NoteID, prelude::*};
+
+pub(super) struct PreviewChange {
+    pub(super) note_id: NoteID,
+    pub(super) field_name: String,
+    pub(super) before: String,
+    pub(super) after: String,
+    pub(super) match_count: u32,
+}
+
+impl Collection {
+    /// Like `find_and_replace`, but reports the changes that would be made
+    /// without committing them. `replacement` may reference capture groups
+    /// from `search` via `$1` or `${name}`, same as the committing path.
+    pub(crate) fn find_and_replace_preview(
+        &mut self,
+        nids: Vec<NoteID>,
+        search: &str,
+        replacement: &str,
+        field_name: Option<String>,
+    ) -> Result<Vec<PreviewChange>> {
+        let re = Regex::new(search).map_err(|e| AnkiError::invalid_input(e.to_string()))?;
+        let mut changes = vec![];
+
+        for nid in nids {
+            let note = match self.storage.get_note(nid)? {
+                Some(note) => note,
+                None => continue,
+            };
+            let nt = self
+                .get_notetype(note.notetype_id)?
+                .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
+
+            for (ord, field) in note.fields().iter().enumerate() {
+                let this_field_name = nt
+                    .fields
+                    .get(ord)
+                    .map(|f| f.name.clone())
+                    .unwrap_or_default();
+                if let Some(ref wanted) = field_name {
+                    if wanted != &this_field_name {
+                        continue;
+                    }
+                }
+
+                let match_count = re.find_iter(field).count() as u32;
+                if match_count == 0 {
+                    continue;
+                }
+
+                changes.push(PreviewChange {
+                    note_id: nid,
+                    field_name: this_field_name,
+                    before: field.clone(),
+                    after: re.replace_all(field, replacement).into_owned(),
+                    match_count,
+                });
+            }
+        }
+
+        Ok(changes)
+    }
+}
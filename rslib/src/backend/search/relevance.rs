@@ -0,0 +1,181 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! BM25 relevance scoring for the `Relevance` sort mode. The parsed search
+//! tree normally lives in the `search` module; `text_terms_in_search()` below
+//! is a conservative stand-in that just keeps whitespace-separated tokens
+//! with no `qualifier:` prefix, since structural qualifiers (deck:, is:,
+//! etc.) should act as filters only and never participate in scoring.
+
+use std::collections::HashMap;
+
+use crate::{card::CardID, notes::NoteID, prelude::*};
+
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+pub(super) fn text_terms_in_search(search: &str) -> Vec<String> {
+    search
+        .split_whitespace()
+        .filter(|token| !token.contains(':'))
+        .map(|token| token.trim_matches('"').to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|w| w.to_ascii_lowercase())
+        .collect()
+}
+
+/// Score each note against `terms` with Okapi BM25. `notes` is the full
+/// candidate set (so document count and average length are known upfront).
+pub(super) fn bm25_scores(notes: &[(NoteID, String)], terms: &[String]) -> HashMap<NoteID, f32> {
+    if terms.is_empty() || notes.is_empty() {
+        return notes.iter().map(|(nid, _)| (*nid, 0.0)).collect();
+    }
+
+    let docs: Vec<(NoteID, Vec<String>)> = notes
+        .iter()
+        .map(|(nid, text)| (*nid, tokenize(text)))
+        .collect();
+
+    let n = docs.len() as f32;
+    let avgdl = docs.iter().map(|(_, toks)| toks.len()).sum::<usize>() as f32 / n;
+
+    let mut doc_freq: HashMap<&str, usize> = HashMap::new();
+    for (_, toks) in &docs {
+        let unique: std::collections::HashSet<&str> = toks.iter().map(String::as_str).collect();
+        for term in &unique {
+            *doc_freq.entry(term).or_insert(0) += 1;
+        }
+    }
+
+    let idf = |term: &str| -> f32 {
+        let n_t = *doc_freq.get(term).unwrap_or(&0) as f32;
+        ((n - n_t + 0.5) / (n_t + 0.5) + 1.0).ln()
+    };
+
+    docs.into_iter()
+        .map(|(nid, toks)| {
+            let dl = toks.len() as f32;
+            let score = terms
+                .iter()
+                .map(|term| {
+                    let f = toks.iter().filter(|t| t.as_str() == term).count() as f32;
+                    if f == 0.0 {
+                        return 0.0;
+                    }
+                    idf(term) * (f * (K1 + 1.0)) / (f + K1 * (1.0 - B + B * dl / avgdl))
+                })
+                .sum();
+            (nid, score)
+        })
+        .collect()
+}
+
+impl Collection {
+    /// Rank the already-filtered candidate set of notes by BM25 relevance to
+    /// the text terms of `search`, highest score first.
+    pub(crate) fn rank_notes_by_relevance(
+        &mut self,
+        search: &str,
+        nids: Vec<NoteID>,
+    ) -> Result<Vec<NoteID>> {
+        let terms = text_terms_in_search(search);
+        let candidates = nids
+            .iter()
+            .filter_map(|&nid| {
+                self.storage
+                    .get_note(nid)
+                    .ok()
+                    .flatten()
+                    .map(|note| (nid, note.fields().join(" ")))
+            })
+            .collect::<Vec<_>>();
+        let scores = bm25_scores(&candidates, &terms);
+        let mut nids = nids;
+        nids.sort_by(|a, b| {
+            scores
+                .get(b)
+                .unwrap_or(&0.0)
+                .partial_cmp(scores.get(a).unwrap_or(&0.0))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Ok(nids)
+    }
+
+    /// As above, but returns note ids directly rather than ranking a
+    /// caller-supplied candidate set: runs `search` as a plain (unordered)
+    /// note search to gather candidates, then ranks them by BM25.
+    pub(crate) fn search_notes_by_relevance(&mut self, search: &str) -> Result<Vec<NoteID>> {
+        let nids = self.search_notes(search)?;
+        self.rank_notes_by_relevance(search, nids)
+    }
+
+    /// As above, but for a card id search: cards sharing a note share its
+    /// relevance score.
+    pub(crate) fn search_cards_by_relevance(&mut self, search: &str) -> Result<Vec<CardID>> {
+        let cids = self.search_cards(search, crate::search::SortMode::NoOrder)?;
+        let cards: Vec<_> = cids
+            .iter()
+            .filter_map(|&cid| self.storage.get_card(cid).ok().flatten())
+            .collect();
+        let nids: Vec<NoteID> = {
+            let mut seen = std::collections::HashSet::new();
+            cards
+                .iter()
+                .filter(|c| seen.insert(c.note_id))
+                .map(|c| c.note_id)
+                .collect()
+        };
+        let terms = text_terms_in_search(search);
+        let candidates = nids
+            .iter()
+            .filter_map(|&nid| {
+                self.storage
+                    .get_note(nid)
+                    .ok()
+                    .flatten()
+                    .map(|note| (nid, note.fields().join(" ")))
+            })
+            .collect::<Vec<_>>();
+        let scores = bm25_scores(&candidates, &terms);
+        let mut cards = cards;
+        cards.sort_by(|a, b| {
+            scores
+                .get(&b.note_id)
+                .unwrap_or(&0.0)
+                .partial_cmp(scores.get(&a.note_id).unwrap_or(&0.0))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Ok(cards.into_iter().map(|c| c.id).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn terms_skip_qualifiers() {
+        assert_eq!(
+            text_terms_in_search("deck:French hello world is:due"),
+            vec!["hello".to_string(), "world".to_string()]
+        );
+    }
+
+    #[test]
+    fn ranks_better_matches_first() {
+        let notes = vec![
+            (NoteID(1), "the quick brown fox".to_string()),
+            (NoteID(2), "quick quick quick fox jumps".to_string()),
+            (NoteID(3), "nothing related here".to_string()),
+        ];
+        let terms = vec!["quick".to_string()];
+        let scores = bm25_scores(&notes, &terms);
+        assert!(scores[&NoteID(2)] > scores[&NoteID(1)]);
+        assert_eq!(scores[&NoteID(3)], 0.0);
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Subtree roll-up aggregation. Given per-deck leaf values, computes every
+//! deck's aggregate over itself and all its descendants in a single
+//! post-order pass, instead of the repeated one-ancestor-at-a-time walks
+//! `update_deck_stats`/`extend_limits` used to do. New roll-up statistics
+//! can be added by picking an `AggrKind`, without writing new propagation
+//! code.
+
+use std::collections::HashMap;
+
+use super::{immediate_parent_name, DeckID};
+
+/// How child values are combined into a parent's aggregate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AggrKind {
+    /// Integer addition - used for studied counts and milliseconds.
+    Sum,
+    Max,
+    Min,
+    /// An idempotent, order-independent combine such as bitwise AND/OR, used
+    /// for per-subtree flags (e.g. "are all decks in this subtree
+    /// collapsed?"). Must be a semilattice combine so recomputation is
+    /// deterministic regardless of the order children are folded in.
+    Meet(fn(i64, i64) -> i64),
+}
+
+impl AggrKind {
+    fn combine(self, a: i64, b: i64) -> i64 {
+        match self {
+            AggrKind::Sum => a + b,
+            AggrKind::Max => a.max(b),
+            AggrKind::Min => a.min(b),
+            AggrKind::Meet(f) => f(a, b),
+        }
+    }
+}
+
+/// A deck's own leaf value, and its combined value including all
+/// descendants.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct Agg {
+    pub(crate) own: i64,
+    pub(crate) subtree: i64,
+}
+
+/// Computes, for every deck with a leaf value (plus any ancestor implied by
+/// `\x1f`-separated names, even if it has no leaf value of its own), the
+/// combined aggregate of itself and its descendants.
+///
+/// `leaves` maps a deck to its own value and its native (machine) name - the
+/// name is needed to derive the parent hierarchy via `immediate_parent_name`,
+/// since this operates purely on names rather than querying storage per
+/// ancestor. A missing intermediate parent (one with no leaf value and not
+/// present in `leaves`) is treated as an implicit empty node that still
+/// participates in the roll-up.
+pub(crate) fn aggregate_subtrees(
+    leaves: &[(DeckID, String, i64)],
+    kind: AggrKind,
+) -> HashMap<DeckID, Agg> {
+    // children keyed by native parent name, so we can DFS from the roots
+    // without needing the DB again
+    let mut children_of: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut id_by_name: HashMap<&str, DeckID> = HashMap::new();
+    let mut own_by_name: HashMap<&str, i64> = HashMap::new();
+    // every node that participates in the roll-up, including implicit
+    // ancestors that have no leaf value of their own
+    let mut all_names: HashMap<&str, ()> = HashMap::new();
+
+    for (did, name, value) in leaves {
+        id_by_name.insert(name.as_str(), *did);
+        own_by_name.insert(name.as_str(), *value);
+        all_names.insert(name.as_str(), ());
+        let mut name = name.as_str();
+        while let Some(parent) = immediate_parent_name(name) {
+            all_names.insert(parent, ());
+            let siblings = children_of.entry(parent).or_default();
+            if !siblings.contains(&name) {
+                siblings.push(name);
+            }
+            name = parent;
+        }
+    }
+
+    let mut out = HashMap::new();
+    let roots: Vec<&str> = all_names
+        .keys()
+        .copied()
+        .filter(|name| immediate_parent_name(name).is_none())
+        .collect();
+    for root in roots {
+        fold(root, &children_of, &own_by_name, &id_by_name, kind, &mut out);
+    }
+    out
+}
+
+/// Post-order fold: visit every child first, then combine their subtree
+/// totals with this node's own value.
+fn fold<'a>(
+    name: &'a str,
+    children_of: &HashMap<&'a str, Vec<&'a str>>,
+    own_by_name: &HashMap<&'a str, i64>,
+    id_by_name: &HashMap<&'a str, DeckID>,
+    kind: AggrKind,
+    out: &mut HashMap<DeckID, Agg>,
+) -> i64 {
+    // an implicit parent has no own value, so it contributes only its
+    // children's values rather than a 0 that would skew Min/Max/Meet
+    let own = own_by_name.get(name).copied();
+    let mut subtree = own;
+    if let Some(children) = children_of.get(name) {
+        for &child in children {
+            let child_subtree = fold(child, children_of, own_by_name, id_by_name, kind, out);
+            subtree = Some(match subtree {
+                Some(acc) => kind.combine(acc, child_subtree),
+                None => child_subtree,
+            });
+        }
+    }
+
+    if let Some(&did) = id_by_name.get(name) {
+        out.insert(
+            did,
+            Agg {
+                own: own.unwrap_or(0),
+                subtree: subtree.unwrap_or(0),
+            },
+        );
+    }
+
+    subtree.unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sum_rolls_up_through_implicit_parent() {
+        // "top::foo" has no leaf value of its own, but must still exist as an
+        // implicit node so foo::bar and foo::baz roll up through it into "top"
+        let leaves = vec![
+            (DeckID(1), "top".to_string(), 1),
+            (DeckID(2), "top\x1ffoo\x1fbar".to_string(), 3),
+            (DeckID(3), "top\x1ffoo\x1fbaz".to_string(), 4),
+        ];
+        let aggs = aggregate_subtrees(&leaves, AggrKind::Sum);
+        assert_eq!(aggs[&DeckID(1)], Agg { own: 1, subtree: 8 });
+        assert_eq!(aggs[&DeckID(2)], Agg { own: 3, subtree: 3 });
+        assert_eq!(aggs[&DeckID(3)], Agg { own: 4, subtree: 4 });
+    }
+
+    #[test]
+    fn max_is_order_independent() {
+        let leaves = vec![
+            (DeckID(1), "top".to_string(), 1),
+            (DeckID(2), "top\x1fa".to_string(), 5),
+            (DeckID(3), "top\x1fb".to_string(), 2),
+        ];
+        let aggs = aggregate_subtrees(&leaves, AggrKind::Max);
+        assert_eq!(aggs[&DeckID(1)].subtree, 5);
+    }
+
+    #[test]
+    fn meet_combines_bitwise_and() {
+        let leaves = vec![
+            (DeckID(1), "top".to_string(), 1),
+            (DeckID(2), "top\x1fa".to_string(), 1),
+            (DeckID(3), "top\x1fb".to_string(), 0),
+        ];
+        let aggs = aggregate_subtrees(&leaves, AggrKind::Meet(|a, b| a & b));
+        // not all decks in the subtree are collapsed, so the top's meet is 0
+        assert_eq!(aggs[&DeckID(1)].subtree, 0);
+    }
+}
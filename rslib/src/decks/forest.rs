@@ -0,0 +1,743 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! An in-memory deck forest backed by a generational arena, used to batch-
+//! validate reparenting moves. `reparented_name`/`add_or_update_deck` detect
+//! a drop onto self or a descendant by comparing `\x1f`-separated name
+//! prefixes, one move at a time. That's fine for a single drag, but doesn't
+//! let the GUI validate and apply a whole batch of independent moves
+//! atomically, and re-derives the same prefix relationship from scratch for
+//! every single deck involved. Here, parent/first-child/next-sibling links
+//! are maintained directly on the arena nodes, so a cycle check is a parent
+//! walk rather than a string comparison, and a whole batch can be applied to
+//! one throwaway forest before any name is derived.
+//!
+//! The sibling chain also gives each deck an explicit position among its
+//! siblings, not just a name - `SiblingPosition`/`move_deck_to_position`
+//! let a single move land at a specific spot (before/after a sibling, or
+//! at an index) instead of always joining at the end, and hand back dense
+//! ordinals for the children of the old and new parent.
+
+use std::collections::HashMap;
+
+use super::{immediate_parent_name, DeckID};
+
+/// An index into a `DeckForest`, paired with a generation counter so a
+/// handle obtained before a slot was freed and reused can never alias its
+/// new occupant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct NodeIdx {
+    slot: usize,
+    generation: u32,
+}
+
+struct Slot {
+    generation: u32,
+    node: Option<Node>,
+}
+
+struct Node {
+    did: DeckID,
+    /// This node's own path component, e.g. "bar" for "foo\x1fbar".
+    leaf: String,
+    parent: Option<NodeIdx>,
+    first_child: Option<NodeIdx>,
+    next_sibling: Option<NodeIdx>,
+}
+
+/// Where a moved deck should land among its new parent's children. Lets
+/// the caller express the same "drop between these two decks" gesture the
+/// deck list UI offers, rather than always appending at the end like
+/// `reparent`/`reparent_decks_batch` do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SiblingPosition {
+    /// Land as the `usize`th child (0-based), clamped to the number of
+    /// other children `new_parent` ends up with.
+    Index(usize),
+    /// Land immediately before this sibling.
+    Before(DeckID),
+    /// Land immediately after this sibling.
+    After(DeckID),
+    /// Land as the last child.
+    Append,
+}
+
+/// Why a requested move was rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DeckForestError {
+    /// The deck being moved isn't in the forest.
+    UnknownDeck(DeckID),
+    /// The requested new parent isn't in the forest.
+    UnknownParent(DeckID),
+    /// The move would make a deck its own ancestor.
+    Cycle(DeckID),
+}
+
+pub(crate) struct DeckForest {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    by_id: HashMap<DeckID, NodeIdx>,
+    /// Head of the top-level sibling chain, mirroring a node's `first_child`
+    /// but for decks with no parent.
+    root: Option<NodeIdx>,
+}
+
+impl DeckForest {
+    /// Builds a forest from a flat list of `(DeckID, native_name)` pairs.
+    /// Parents are inferred from `\x1f`-separated name prefixes, exactly as
+    /// `immediate_parent_name` does elsewhere; a name with no deck of its
+    /// own for an implied parent is simply treated as a top-level node.
+    pub(crate) fn build(decks: &[(DeckID, String)]) -> Self {
+        let mut forest = DeckForest {
+            slots: Vec::with_capacity(decks.len()),
+            free: Vec::new(),
+            by_id: HashMap::with_capacity(decks.len()),
+            root: None,
+        };
+
+        let id_by_name: HashMap<&str, DeckID> = decks
+            .iter()
+            .map(|(did, name)| (name.as_str(), *did))
+            .collect();
+
+        // insert every node unparented first, so `by_id` is complete before
+        // we need to look up any parent's index
+        for (did, name) in decks {
+            let leaf = name.rsplit('\x1f').next().unwrap_or(name).to_string();
+            let idx = forest.alloc(Node {
+                did: *did,
+                leaf,
+                parent: None,
+                first_child: None,
+                next_sibling: None,
+            });
+            forest.by_id.insert(*did, idx);
+        }
+
+        for (did, name) in decks {
+            let parent_idx = immediate_parent_name(name)
+                .and_then(|parent_name| id_by_name.get(parent_name))
+                .and_then(|parent_did| forest.by_id.get(parent_did).copied());
+            let idx = forest.by_id[did];
+            forest.attach(idx, parent_idx);
+        }
+
+        forest
+    }
+
+    /// Like `build`, but orders each parent's children by an explicit
+    /// `(DeckID, ordinal)` list instead of `decks`' own order, so the
+    /// forest's intrinsic sibling order matches whatever was last
+    /// persisted rather than however `decks` happened to be fetched.
+    /// Decks missing from `ordinals` sort after every deck present in it.
+    pub(crate) fn build_with_ordinals(
+        decks: &[(DeckID, String)],
+        ordinals: &[(DeckID, u32)],
+    ) -> Self {
+        let ordinal_of: HashMap<DeckID, u32> = ordinals.iter().copied().collect();
+        let mut sorted = decks.to_vec();
+        sorted.sort_by_key(|(did, _)| ordinal_of.get(did).copied().unwrap_or(u32::MAX));
+        Self::build(&sorted)
+    }
+
+    fn alloc(&mut self, node: Node) -> NodeIdx {
+        if let Some(slot) = self.free.pop() {
+            let generation = self.slots[slot].generation;
+            self.slots[slot].node = Some(node);
+            NodeIdx { slot, generation }
+        } else {
+            let slot = self.slots.len();
+            self.slots.push(Slot {
+                generation: 0,
+                node: Some(node),
+            });
+            NodeIdx { slot, generation: 0 }
+        }
+    }
+
+    fn get(&self, idx: NodeIdx) -> Option<&Node> {
+        self.slots
+            .get(idx.slot)
+            .filter(|slot| slot.generation == idx.generation)
+            .and_then(|slot| slot.node.as_ref())
+    }
+
+    fn get_mut(&mut self, idx: NodeIdx) -> Option<&mut Node> {
+        self.slots
+            .get_mut(idx.slot)
+            .filter(|slot| slot.generation == idx.generation)
+            .and_then(|slot| slot.node.as_mut())
+    }
+
+    pub(crate) fn idx_of(&self, did: DeckID) -> Option<NodeIdx> {
+        self.by_id.get(&did).copied()
+    }
+
+    fn children_head(&self, parent: Option<NodeIdx>) -> Option<NodeIdx> {
+        match parent {
+            Some(p) => self.get(p).and_then(|n| n.first_child),
+            None => self.root,
+        }
+    }
+
+    /// The children of `parent` (or the top level, if `None`), in sibling
+    /// order.
+    pub(crate) fn children_of(&self, parent: Option<NodeIdx>) -> Vec<NodeIdx> {
+        let mut out = Vec::new();
+        let mut cur = self.children_head(parent);
+        while let Some(c) = cur {
+            out.push(c);
+            cur = self.get(c).and_then(|n| n.next_sibling);
+        }
+        out
+    }
+
+    /// `did`'s current parent, or `None` if it's top-level (or unknown).
+    pub(crate) fn parent_of(&self, did: DeckID) -> Option<DeckID> {
+        let idx = self.idx_of(did)?;
+        let parent_idx = self.get(idx)?.parent?;
+        self.get(parent_idx).map(|n| n.did)
+    }
+
+    /// The dense 0-based ordinal of each child of `parent`, in sibling
+    /// order - ready to persist after a move shifts other siblings up or
+    /// down to close the gap it left, or make room for it.
+    pub(crate) fn sibling_ordinals(&self, parent: Option<DeckID>) -> Vec<(DeckID, u32)> {
+        let parent_idx = parent.and_then(|p| self.idx_of(p));
+        self.children_of(parent_idx)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, idx)| self.get(idx).map(|n| (n.did, i as u32)))
+            .collect()
+    }
+
+    fn set_children_head(&mut self, parent: Option<NodeIdx>, head: Option<NodeIdx>) {
+        match parent {
+            Some(p) => {
+                if let Some(n) = self.get_mut(p) {
+                    n.first_child = head;
+                }
+            }
+            None => self.root = head,
+        }
+    }
+
+    /// Unlinks `idx` from its current parent's (or the forest's) sibling
+    /// chain. Singly-linked siblings mean this is a scan rather than an
+    /// O(1) unlink, but keeps the node small - reparenting is not a hot
+    /// path compared to review scheduling.
+    fn detach(&mut self, idx: NodeIdx) {
+        let parent = self.get(idx).and_then(|n| n.parent);
+        let head = self.children_head(parent);
+        if head == Some(idx) {
+            let next = self.get(idx).and_then(|n| n.next_sibling);
+            self.set_children_head(parent, next);
+        } else {
+            let mut cur = head;
+            while let Some(c) = cur {
+                let next = self.get(c).and_then(|n| n.next_sibling);
+                if next == Some(idx) {
+                    let after = self.get(idx).and_then(|n| n.next_sibling);
+                    if let Some(n) = self.get_mut(c) {
+                        n.next_sibling = after;
+                    }
+                    break;
+                }
+                cur = next;
+            }
+        }
+        if let Some(n) = self.get_mut(idx) {
+            n.parent = None;
+            n.next_sibling = None;
+        }
+    }
+
+    /// Links `idx` in as a child of `new_parent` (or a new top-level root,
+    /// if `None`), at the end of its existing children - the default, and
+    /// the only option `reparent`/`reparent_decks_batch` need, since
+    /// neither expresses a target position among siblings.
+    fn attach(&mut self, idx: NodeIdx, new_parent: Option<NodeIdx>) {
+        self.attach_at(idx, new_parent, None);
+    }
+
+    /// Links `idx` in as a child of `new_parent`, immediately before
+    /// `before` if given (which must currently be one of `new_parent`'s
+    /// children), or at the end of the existing children otherwise.
+    fn attach_at(&mut self, idx: NodeIdx, new_parent: Option<NodeIdx>, before: Option<NodeIdx>) {
+        if let Some(n) = self.get_mut(idx) {
+            n.parent = new_parent;
+        }
+
+        let before = before.filter(|&b| Some(b) != Some(idx));
+        match before {
+            Some(before_idx) => {
+                let head = self.children_head(new_parent);
+                if head == Some(before_idx) {
+                    if let Some(n) = self.get_mut(idx) {
+                        n.next_sibling = Some(before_idx);
+                    }
+                    self.set_children_head(new_parent, Some(idx));
+                } else {
+                    let mut cur = head;
+                    while let Some(c) = cur {
+                        let next = self.get(c).and_then(|n| n.next_sibling);
+                        if next == Some(before_idx) {
+                            if let Some(n) = self.get_mut(idx) {
+                                n.next_sibling = Some(before_idx);
+                            }
+                            if let Some(n) = self.get_mut(c) {
+                                n.next_sibling = Some(idx);
+                            }
+                            break;
+                        }
+                        cur = next;
+                    }
+                }
+            }
+            None => {
+                // append - singly-linked siblings mean finding the current
+                // tail is a walk rather than an O(1) push, same tradeoff as
+                // `detach`'s unlink
+                match self.children_head(new_parent) {
+                    None => {
+                        if let Some(n) = self.get_mut(idx) {
+                            n.next_sibling = None;
+                        }
+                        self.set_children_head(new_parent, Some(idx));
+                    }
+                    Some(mut tail) => {
+                        while let Some(next) = self.get(tail).and_then(|n| n.next_sibling) {
+                            tail = next;
+                        }
+                        if let Some(n) = self.get_mut(idx) {
+                            n.next_sibling = None;
+                        }
+                        if let Some(n) = self.get_mut(tail) {
+                            n.next_sibling = Some(idx);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resolves a `SiblingPosition` to the node it means to land in front
+    /// of, under `parent` - `None` means "append". A `Before`/`After`
+    /// sibling that isn't actually a child of `parent` falls back to
+    /// append, the same way `reparented_name` no-ops an impossible drop
+    /// rather than erroring.
+    fn resolve_position(
+        &self,
+        parent: Option<NodeIdx>,
+        position: SiblingPosition,
+    ) -> Option<NodeIdx> {
+        match position {
+            SiblingPosition::Append => None,
+            SiblingPosition::Index(i) => self.children_of(parent).into_iter().nth(i),
+            SiblingPosition::Before(did) => self
+                .idx_of(did)
+                .filter(|&c| self.get(c).and_then(|n| n.parent) == parent),
+            SiblingPosition::After(did) => {
+                let sibling = self
+                    .idx_of(did)
+                    .filter(|&c| self.get(c).and_then(|n| n.parent) == parent)?;
+                self.get(sibling).and_then(|n| n.next_sibling)
+            }
+        }
+    }
+
+    /// Is `candidate` the same node as `ancestor`, or one of its
+    /// descendants? Walks parent links rather than comparing name prefixes.
+    fn is_self_or_descendant(&self, ancestor: NodeIdx, candidate: NodeIdx) -> bool {
+        let mut cur = Some(candidate);
+        while let Some(c) = cur {
+            if c == ancestor {
+                return true;
+            }
+            cur = self.get(c).and_then(|n| n.parent);
+        }
+        false
+    }
+
+    /// Moves `did` to be a child of `new_parent` (or to the top level, if
+    /// `None`), rejecting the move if it would create a cycle.
+    pub(crate) fn reparent(
+        &mut self,
+        did: DeckID,
+        new_parent: Option<DeckID>,
+    ) -> Result<(), DeckForestError> {
+        let idx = self.idx_of(did).ok_or(DeckForestError::UnknownDeck(did))?;
+        let new_parent_idx = match new_parent {
+            Some(p) => Some(
+                self.idx_of(p)
+                    .ok_or(DeckForestError::UnknownParent(p))?,
+            ),
+            None => None,
+        };
+
+        if let Some(p) = new_parent_idx {
+            if self.is_self_or_descendant(idx, p) {
+                return Err(DeckForestError::Cycle(did));
+            }
+        }
+
+        self.detach(idx);
+        self.attach(idx, new_parent_idx);
+        Ok(())
+    }
+
+    /// Like `reparent`, but lands `did` at a specific position among
+    /// `new_parent`'s children instead of always at the end.
+    pub(crate) fn reparent_with_position(
+        &mut self,
+        did: DeckID,
+        new_parent: Option<DeckID>,
+        position: SiblingPosition,
+    ) -> Result<(), DeckForestError> {
+        let idx = self.idx_of(did).ok_or(DeckForestError::UnknownDeck(did))?;
+        let new_parent_idx = match new_parent {
+            Some(p) => Some(
+                self.idx_of(p)
+                    .ok_or(DeckForestError::UnknownParent(p))?,
+            ),
+            None => None,
+        };
+
+        if let Some(p) = new_parent_idx {
+            if self.is_self_or_descendant(idx, p) {
+                return Err(DeckForestError::Cycle(did));
+            }
+        }
+
+        self.detach(idx);
+        let before = self.resolve_position(new_parent_idx, position);
+        self.attach_at(idx, new_parent_idx, before);
+        Ok(())
+    }
+
+    /// Reconstructs a deck's full native name from the current arena links.
+    pub(crate) fn native_name(&self, did: DeckID) -> Option<String> {
+        let mut parts = Vec::new();
+        let mut cur = self.idx_of(did);
+        while let Some(c) = cur {
+            let node = self.get(c)?;
+            parts.push(node.leaf.as_str());
+            cur = node.parent;
+        }
+        parts.reverse();
+        Some(parts.join("\x1f"))
+    }
+}
+
+/// Validates and applies a whole batch of independent reparenting moves at
+/// once: every move is checked against the forest as it would look *after*
+/// the previous moves in the batch, and if any move in the batch would
+/// create a cycle, the whole batch is rejected with no names derived -
+/// there's no partial application to undo, since everything happens on a
+/// throwaway forest built from `decks`.
+///
+/// Returns the `(DeckID, native_name)` pairs whose name actually changed as
+/// a result of the batch, ready to apply through the existing
+/// `add_or_update_deck` machinery.
+pub(crate) fn reparent_decks_batch(
+    decks: &[(DeckID, String)],
+    moves: &[(DeckID, Option<DeckID>)],
+) -> Result<Vec<(DeckID, String)>, DeckForestError> {
+    let mut forest = DeckForest::build(decks);
+    for &(did, new_parent) in moves {
+        forest.reparent(did, new_parent)?;
+    }
+
+    Ok(decks
+        .iter()
+        .filter_map(|(did, old_name)| {
+            let new_name = forest.native_name(*did)?;
+            if &new_name != old_name {
+                Some((*did, new_name))
+            } else {
+                None
+            }
+        })
+        .collect())
+}
+
+/// Moves a single deck to `new_parent`, landing it at `position` among its
+/// new siblings, on a throwaway forest whose sibling order is seeded from
+/// `ordinals` rather than `decks`' own order.
+///
+/// Returns the `(DeckID, native_name)` pairs to apply through
+/// `add_or_update_deck`/`rename_deck`, same as `reparent_decks_batch`, plus
+/// the dense `(DeckID, ordinal)` pairs to persist for every child of the
+/// old parent (closing the gap the move left behind) and, if the deck
+/// changed parent, every child of the new parent (making room for it) -
+/// including siblings whose ordinal ends up unchanged.
+pub(crate) fn move_deck_to_position(
+    decks: &[(DeckID, String)],
+    ordinals: &[(DeckID, u32)],
+    did: DeckID,
+    new_parent: Option<DeckID>,
+    position: SiblingPosition,
+) -> Result<(Vec<(DeckID, String)>, Vec<(DeckID, u32)>), DeckForestError> {
+    let mut forest = DeckForest::build_with_ordinals(decks, ordinals);
+    let old_parent = forest.parent_of(did);
+
+    forest.reparent_with_position(did, new_parent, position)?;
+
+    let mut changed_ordinals = forest.sibling_ordinals(old_parent);
+    if new_parent != old_parent {
+        changed_ordinals.extend(forest.sibling_ordinals(new_parent));
+    }
+
+    let changed_names = decks
+        .iter()
+        .filter_map(|(did, old_name)| {
+            let new_name = forest.native_name(*did)?;
+            if &new_name != old_name {
+                Some((*did, new_name))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    Ok((changed_names, changed_ordinals))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decks(entries: &[(i64, &str)]) -> Vec<(DeckID, String)> {
+        entries
+            .iter()
+            .map(|(id, name)| (DeckID(*id), name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn builds_and_reports_names_unchanged() {
+        let decks = decks(&[(1, "foo"), (2, "foo\x1fbar")]);
+        let forest = DeckForest::build(&decks);
+        assert_eq!(forest.native_name(DeckID(1)).as_deref(), Some("foo"));
+        assert_eq!(
+            forest.native_name(DeckID(2)).as_deref(),
+            Some("foo\x1fbar")
+        );
+    }
+
+    #[test]
+    fn reparent_onto_new_parent_updates_name() {
+        let decks = decks(&[(1, "foo"), (2, "bar")]);
+        let mut forest = DeckForest::build(&decks);
+        forest.reparent(DeckID(1), Some(DeckID(2))).unwrap();
+        assert_eq!(
+            forest.native_name(DeckID(1)).as_deref(),
+            Some("bar\x1ffoo")
+        );
+    }
+
+    #[test]
+    fn reparent_onto_self_is_a_cycle() {
+        let decks = decks(&[(1, "foo")]);
+        let mut forest = DeckForest::build(&decks);
+        assert_eq!(
+            forest.reparent(DeckID(1), Some(DeckID(1))),
+            Err(DeckForestError::Cycle(DeckID(1)))
+        );
+    }
+
+    #[test]
+    fn reparent_onto_own_descendant_is_a_cycle() {
+        let decks = decks(&[(1, "foo"), (2, "foo\x1fbar"), (3, "foo\x1fbar\x1fbaz")]);
+        let mut forest = DeckForest::build(&decks);
+        assert_eq!(
+            forest.reparent(DeckID(1), Some(DeckID(3))),
+            Err(DeckForestError::Cycle(DeckID(1)))
+        );
+    }
+
+    #[test]
+    fn cascades_to_descendants() {
+        let decks = decks(&[(1, "foo"), (2, "foo\x1fbar"), (3, "baz")]);
+        let mut forest = DeckForest::build(&decks);
+        forest.reparent(DeckID(1), Some(DeckID(3))).unwrap();
+        assert_eq!(
+            forest.native_name(DeckID(2)).as_deref(),
+            Some("baz\x1ffoo\x1fbar")
+        );
+    }
+
+    #[test]
+    fn batch_returns_only_changed_names() {
+        let decks = decks(&[(1, "foo"), (2, "bar"), (3, "untouched")]);
+        let result =
+            reparent_decks_batch(&decks, &[(DeckID(1), Some(DeckID(2)))]).unwrap();
+        assert_eq!(result, vec![(DeckID(1), "bar\x1ffoo".to_string())]);
+    }
+
+    #[test]
+    fn batch_is_rejected_atomically_on_any_cycle() {
+        // first move is fine on its own, but the second move tries to drop
+        // bar onto its own new child (foo, just moved under it)
+        let decks = decks(&[(1, "foo"), (2, "bar")]);
+        let result = reparent_decks_batch(
+            &decks,
+            &[
+                (DeckID(1), Some(DeckID(2))),
+                (DeckID(2), Some(DeckID(1))),
+            ],
+        );
+        assert_eq!(result, Err(DeckForestError::Cycle(DeckID(2))));
+    }
+
+    #[test]
+    fn batch_rejects_unknown_deck_or_parent() {
+        let decks = decks(&[(1, "foo")]);
+        assert_eq!(
+            reparent_decks_batch(&decks, &[(DeckID(99), None)]),
+            Err(DeckForestError::UnknownDeck(DeckID(99)))
+        );
+        assert_eq!(
+            reparent_decks_batch(&decks, &[(DeckID(1), Some(DeckID(99)))]),
+            Err(DeckForestError::UnknownParent(DeckID(99)))
+        );
+    }
+
+    #[test]
+    fn build_preserves_input_order_as_sibling_order() {
+        let decks = decks(&[(1, "foo"), (2, "foo\x1fa"), (3, "foo\x1fb"), (4, "foo\x1fc")]);
+        let forest = DeckForest::build(&decks);
+        assert_eq!(
+            forest
+                .sibling_ordinals(Some(DeckID(1)))
+                .into_iter()
+                .map(|(did, _)| did.0)
+                .collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn move_into_middle_of_existing_siblings() {
+        // a, b, c already under "foo"; dropping a lone deck "x" between a
+        // and b should land it there, not at the end
+        let decks = decks(&[
+            (1, "foo"),
+            (2, "foo\x1fa"),
+            (3, "foo\x1fb"),
+            (4, "foo\x1fc"),
+            (5, "x"),
+        ]);
+        let ordinals = vec![
+            (DeckID(2), 0),
+            (DeckID(3), 1),
+            (DeckID(4), 2),
+            (DeckID(1), 0),
+            (DeckID(5), 0),
+        ];
+        let (renames, new_ordinals) = move_deck_to_position(
+            &decks,
+            &ordinals,
+            DeckID(5),
+            Some(DeckID(1)),
+            SiblingPosition::After(DeckID(2)),
+        )
+        .unwrap();
+
+        assert_eq!(renames, vec![(DeckID(5), "foo\x1fx".to_string())]);
+        let by_id: HashMap<i64, u32> =
+            new_ordinals.iter().map(|(did, ord)| (did.0, *ord)).collect();
+        // "x" left the top level, so the remaining top-level deck ("foo") is
+        // renumbered too, alongside "foo"'s children
+        assert_eq!(by_id.len(), 5);
+        assert_eq!(by_id[&1], 0);
+        assert_eq!(by_id[&2], 0);
+        assert_eq!(by_id[&5], 1);
+        assert_eq!(by_id[&3], 2);
+        assert_eq!(by_id[&4], 3);
+    }
+
+    #[test]
+    fn move_within_same_parent_renumbers_once() {
+        let decks = decks(&[(1, "a"), (2, "b"), (3, "c")]);
+        let ordinals = vec![(DeckID(1), 0), (DeckID(2), 1), (DeckID(3), 2)];
+        let (_, new_ordinals) = move_deck_to_position(
+            &decks,
+            &ordinals,
+            DeckID(3),
+            None,
+            SiblingPosition::Index(0),
+        )
+        .unwrap();
+
+        let by_id: HashMap<i64, u32> =
+            new_ordinals.iter().map(|(did, ord)| (did.0, *ord)).collect();
+        assert_eq!(by_id[&3], 0);
+        assert_eq!(by_id[&1], 1);
+        assert_eq!(by_id[&2], 2);
+    }
+
+    #[test]
+    fn before_and_after_fall_back_to_append_when_sibling_is_elsewhere() {
+        let decks = decks(&[
+            (1, "foo"),
+            (2, "foo\x1fa"),
+            (10, "bar"),
+            (11, "bar\x1fb"),
+            (3, "x"),
+        ]);
+        let ordinals = vec![
+            (DeckID(2), 0),
+            (DeckID(11), 0),
+            (DeckID(1), 0),
+            (DeckID(10), 0),
+            (DeckID(3), 0),
+        ];
+        // "x" is dropped onto "foo", but told to land before a deck ("b")
+        // that actually lives under "bar" - falls back to append
+        let (renames, new_ordinals) = move_deck_to_position(
+            &decks,
+            &ordinals,
+            DeckID(3),
+            Some(DeckID(1)),
+            SiblingPosition::Before(DeckID(11)),
+        )
+        .unwrap();
+        assert_eq!(renames, vec![(DeckID(3), "foo\x1fx".to_string())]);
+        let by_id: HashMap<i64, u32> =
+            new_ordinals.iter().map(|(did, ord)| (did.0, *ord)).collect();
+        assert_eq!(by_id[&2], 0);
+        assert_eq!(by_id[&3], 1);
+
+        // the same holds for landing after a sibling under another parent
+        let (renames, new_ordinals) = move_deck_to_position(
+            &decks,
+            &ordinals,
+            DeckID(3),
+            Some(DeckID(1)),
+            SiblingPosition::After(DeckID(11)),
+        )
+        .unwrap();
+        assert_eq!(renames, vec![(DeckID(3), "foo\x1fx".to_string())]);
+        let by_id: HashMap<i64, u32> =
+            new_ordinals.iter().map(|(did, ord)| (did.0, *ord)).collect();
+        assert_eq!(by_id[&2], 0);
+        assert_eq!(by_id[&3], 1);
+    }
+
+    #[test]
+    fn move_detects_cycle_with_position() {
+        let decks = decks(&[(1, "foo"), (2, "foo\x1fbar")]);
+        let ordinals = vec![(DeckID(1), 0), (DeckID(2), 0)];
+        assert_eq!(
+            move_deck_to_position(
+                &decks,
+                &ordinals,
+                DeckID(1),
+                Some(DeckID(2)),
+                SiblingPosition::Append,
+            ),
+            Err(DeckForestError::Cycle(DeckID(1)))
+        );
+    }
+}
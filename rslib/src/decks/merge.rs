@@ -0,0 +1,251 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Three-way merge over deck trees, analogous to how a VCS merges file
+//! trees. Given a common `base` snapshot and two independently modified
+//! `local` and `remote` snapshots (each mapping deck id to native name),
+//! works out the merged set of names to apply and the ids to delete,
+//! without recreating decks through `get_or_create_normal_deck` and ending
+//! up with `+`-suffixed duplicates whenever both sides touched the same
+//! subtree.
+
+use std::collections::{HashMap, HashSet};
+
+use super::DeckID;
+
+/// A snapshot of the decks that exist in a tree, mapping each deck id to
+/// its native (machine, `\x1f`-separated) name at that point in time.
+pub(crate) type DeckTree = HashMap<DeckID, String>;
+
+/// A merge outcome that was resolved automatically, but still deserves the
+/// user's attention.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DeckMergeConflict {
+    /// Deleted on one side, but renamed or given new cards on the other -
+    /// the deletion was discarded, so the deck survives under whichever
+    /// name (or cards) the other side gave it.
+    DeletedButModified(DeckID),
+    /// Renamed to two different names on each side; local's rename was
+    /// kept.
+    DivergentRename(DeckID),
+}
+
+/// Three-way merges `local` and `remote` against their common `base`.
+///
+/// `local_touched`/`remote_touched` mark deck ids that had cards moved into
+/// them (or were otherwise modified beyond a plain rename) on the
+/// corresponding side since `base` - this only changes the outcome of the
+/// deleted-on-one-side case, where it turns a silent deletion into a
+/// conflict instead of discarding the new cards.
+///
+/// Returns the `(DeckID, native_name)` pairs to apply via
+/// `add_or_update_deck`/`rename_deck`, the ids that should be removed
+/// entirely, and any conflicts that were resolved but still deserve a
+/// mention to the user.
+pub(crate) fn merge_deck_trees(
+    base: &DeckTree,
+    local: &DeckTree,
+    remote: &DeckTree,
+    local_touched: &HashSet<DeckID>,
+    remote_touched: &HashSet<DeckID>,
+) -> (Vec<(DeckID, String)>, Vec<DeckID>, Vec<DeckMergeConflict>) {
+    let mut renames = Vec::new();
+    let mut deletions = Vec::new();
+    let mut conflicts = Vec::new();
+
+    let all_ids: HashSet<DeckID> = base
+        .keys()
+        .chain(local.keys())
+        .chain(remote.keys())
+        .copied()
+        .collect();
+
+    for did in all_ids {
+        match (base.get(&did), local.get(&did), remote.get(&did)) {
+            (None, None, None) => unreachable!("did must appear in at least one snapshot"),
+
+            // added fresh on one side only
+            (None, Some(name), None) => renames.push((did, name.clone())),
+            (None, None, Some(name)) => renames.push((did, name.clone())),
+            // added on both sides under the same id - unify, preferring
+            // local's name if the two disagree on what it should be called
+            (None, Some(l), Some(r)) => {
+                if l != r {
+                    conflicts.push(DeckMergeConflict::DivergentRename(did));
+                }
+                renames.push((did, l.clone()));
+            }
+
+            // deleted on both sides
+            (Some(_), None, None) => deletions.push(did),
+            // deleted on local; keep it if remote renamed or touched it
+            (Some(b), None, Some(r)) => {
+                if r != b || remote_touched.contains(&did) {
+                    conflicts.push(DeckMergeConflict::DeletedButModified(did));
+                    renames.push((did, r.clone()));
+                } else {
+                    deletions.push(did);
+                }
+            }
+            // deleted on remote; keep it if local renamed or touched it
+            (Some(b), Some(l), None) => {
+                if l != b || local_touched.contains(&did) {
+                    conflicts.push(DeckMergeConflict::DeletedButModified(did));
+                    renames.push((did, l.clone()));
+                } else {
+                    deletions.push(did);
+                }
+            }
+
+            // present (or re-added to the same name) on all three
+            (Some(b), Some(l), Some(r)) => match (l == b, r == b) {
+                (true, true) => {}                               // untouched
+                (false, true) => renames.push((did, l.clone())), // renamed locally only
+                (true, false) => renames.push((did, r.clone())), // renamed remotely only
+                (false, false) => {
+                    if l == r {
+                        renames.push((did, l.clone())); // converged on the same name
+                    } else {
+                        conflicts.push(DeckMergeConflict::DivergentRename(did));
+                        renames.push((did, l.clone()));
+                    }
+                }
+            },
+        }
+    }
+
+    (renames, deletions, conflicts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tree(entries: &[(i64, &str)]) -> DeckTree {
+        entries
+            .iter()
+            .map(|(id, name)| (DeckID(*id), name.to_string()))
+            .collect()
+    }
+
+    fn touched(ids: &[i64]) -> HashSet<DeckID> {
+        ids.iter().map(|id| DeckID(*id)).collect()
+    }
+
+    #[test]
+    fn added_on_one_side_is_kept() {
+        let base = tree(&[]);
+        let local = tree(&[(1, "foo")]);
+        let remote = tree(&[]);
+        let (renames, deletions, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[]));
+        assert_eq!(renames, vec![(DeckID(1), "foo".to_string())]);
+        assert!(deletions.is_empty());
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn added_on_both_with_same_name_unifies_without_conflict() {
+        let base = tree(&[]);
+        let local = tree(&[(1, "foo")]);
+        let remote = tree(&[(1, "foo")]);
+        let (renames, _, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[]));
+        assert_eq!(renames, vec![(DeckID(1), "foo".to_string())]);
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn added_on_both_with_different_names_flags_conflict_but_keeps_local() {
+        let base = tree(&[]);
+        let local = tree(&[(1, "foo")]);
+        let remote = tree(&[(1, "bar")]);
+        let (renames, _, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[]));
+        assert_eq!(renames, vec![(DeckID(1), "foo".to_string())]);
+        assert_eq!(conflicts, vec![DeckMergeConflict::DivergentRename(DeckID(1))]);
+    }
+
+    #[test]
+    fn rename_follows_the_side_that_changed() {
+        let base = tree(&[(1, "foo")]);
+        let local = tree(&[(1, "bar")]);
+        let remote = tree(&[(1, "foo")]);
+        let (renames, _, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[]));
+        assert_eq!(renames, vec![(DeckID(1), "bar".to_string())]);
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn convergent_rename_is_kept_without_conflict() {
+        let base = tree(&[(1, "foo")]);
+        let local = tree(&[(1, "bar")]);
+        let remote = tree(&[(1, "bar")]);
+        let (renames, _, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[]));
+        assert_eq!(renames, vec![(DeckID(1), "bar".to_string())]);
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn divergent_rename_flags_conflict_but_keeps_local() {
+        let base = tree(&[(1, "foo")]);
+        let local = tree(&[(1, "bar")]);
+        let remote = tree(&[(1, "baz")]);
+        let (renames, _, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[]));
+        assert_eq!(renames, vec![(DeckID(1), "bar".to_string())]);
+        assert_eq!(conflicts, vec![DeckMergeConflict::DivergentRename(DeckID(1))]);
+    }
+
+    #[test]
+    fn deletion_wins_when_other_side_is_untouched() {
+        let base = tree(&[(1, "foo")]);
+        let local = tree(&[]);
+        let remote = tree(&[(1, "foo")]);
+        let (renames, deletions, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[]));
+        assert!(renames.is_empty());
+        assert_eq!(deletions, vec![DeckID(1)]);
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn deletion_is_overridden_by_a_rename_on_the_other_side() {
+        let base = tree(&[(1, "foo")]);
+        let local = tree(&[]);
+        let remote = tree(&[(1, "foo\x1fbar")]);
+        let (renames, deletions, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[]));
+        assert_eq!(renames, vec![(DeckID(1), "foo\x1fbar".to_string())]);
+        assert!(deletions.is_empty());
+        assert_eq!(conflicts, vec![DeckMergeConflict::DeletedButModified(DeckID(1))]);
+    }
+
+    #[test]
+    fn deletion_is_overridden_by_cards_added_on_the_other_side() {
+        // remote kept the same name, but cards were moved into the deck,
+        // so a plain name comparison wouldn't have caught the conflict
+        let base = tree(&[(1, "foo")]);
+        let local = tree(&[]);
+        let remote = tree(&[(1, "foo")]);
+        let (renames, deletions, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[1]));
+        assert_eq!(renames, vec![(DeckID(1), "foo".to_string())]);
+        assert!(deletions.is_empty());
+        assert_eq!(conflicts, vec![DeckMergeConflict::DeletedButModified(DeckID(1))]);
+    }
+
+    #[test]
+    fn deletion_on_both_sides_is_dropped_silently() {
+        let base = tree(&[(1, "foo")]);
+        let local = tree(&[]);
+        let remote = tree(&[]);
+        let (renames, deletions, conflicts) =
+            merge_deck_trees(&base, &local, &remote, &touched(&[]), &touched(&[]));
+        assert!(renames.is_empty());
+        assert_eq!(deletions, vec![DeckID(1)]);
+        assert!(conflicts.is_empty());
+    }
+}
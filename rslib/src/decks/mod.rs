@@ -1,9 +1,14 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+mod aggregate;
 mod counts;
 mod filtered;
+mod forest;
+mod merge;
+mod radix;
 mod schema11;
+mod simulate;
 mod tree;
 pub(crate) mod undo;
 
@@ -24,9 +29,18 @@ use crate::{
     timestamp::TimestampSecs,
     types::Usn,
 };
+use aggregate::{aggregate_subtrees, AggrKind};
 pub(crate) use counts::DueCounts;
+use forest::{move_deck_to_position, reparent_decks_batch, DeckForestError};
+pub use forest::SiblingPosition;
+pub(crate) use merge::{merge_deck_trees, DeckMergeConflict, DeckTree};
+use radix::DeckNameIndex;
 pub use schema11::DeckSchema11;
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 define_newtype!(DeckID, i64);
 
@@ -210,6 +224,32 @@ impl Collection {
             Ok(None)
         }
     }
+
+    /// Returns the name index, building it from storage on first use. Once
+    /// built it's kept in sync incrementally by every deck add/rename/remove
+    /// path, the same way `deck_cache` is evicted incrementally rather than
+    /// rebuilt wholesale, so this never re-walks storage after the first
+    /// call in a session.
+    fn deck_name_index(&mut self) -> Result<&mut DeckNameIndex> {
+        if self.state.deck_name_index.is_none() {
+            let entries = self
+                .fetch_all_decks()?
+                .into_iter()
+                .map(|deck| (deck.name, deck.id));
+            self.state.deck_name_index = Some(DeckNameIndex::rebuild(entries));
+        }
+        Ok(self.state.deck_name_index.as_mut().unwrap())
+    }
+
+    /// Keeps `deck_name_index` in sync with a single deck's rename, mirroring
+    /// `invalidate_deck_cache_for`'s "touch only what changed" approach
+    /// instead of rebuilding the whole index.
+    fn reindex_deck_rename(&mut self, old_name: &str, new_name: &str, did: DeckID) -> Result<()> {
+        let index = self.deck_name_index()?;
+        index.remove(old_name);
+        index.insert(new_name, did);
+        Ok(())
+    }
 }
 
 impl From<Deck> for DeckProto {
@@ -258,6 +298,29 @@ pub(crate) fn reparented_name(dragged: &str, dropped: Option<&str>) -> Option<St
     }
 }
 
+/// What to do when a deck is being given a name that's already in use by a
+/// different deck. Historically `add_or_update_deck` always behaved like
+/// `Suffix`, silently renaming the colliding deck by appending `+`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeckNameConflict {
+    /// Append `+` to the incoming name until it's unique (previous, and
+    /// still default, behavior).
+    Suffix,
+    /// Move the incoming deck's cards and child decks into the existing
+    /// deck at the colliding name, and retire the incoming deck id. This is
+    /// what a user dragging one deck onto another that shares a subtree
+    /// expects to happen.
+    Merge,
+    /// Return an error instead of silently resolving the collision.
+    Fail,
+}
+
+impl Default for DeckNameConflict {
+    fn default() -> Self {
+        DeckNameConflict::Suffix
+    }
+}
+
 impl Collection {
     pub(crate) fn default_deck_is_empty(&self) -> Result<bool> {
         self.storage.deck_is_empty(DeckID(1))
@@ -266,11 +329,25 @@ impl Collection {
     /// Normalize deck name and rename if not unique. Bumps mtime and usn if
     /// name was changed, but otherwise leaves it the same.
     fn prepare_deck_for_update(&mut self, deck: &mut Deck, usn: Usn) -> Result<()> {
+        self.prepare_deck_for_update_with_policy(deck, usn, DeckNameConflict::Suffix)
+            .map(|_merged| ())
+    }
+
+    /// Like `prepare_deck_for_update`, but lets the caller choose what
+    /// happens on a name collision. Returns `true` if `deck` was merged into
+    /// the existing deck at that name and retired - the caller must not
+    /// persist `deck` under its own id any further in that case.
+    fn prepare_deck_for_update_with_policy(
+        &mut self,
+        deck: &mut Deck,
+        usn: Usn,
+        policy: DeckNameConflict,
+    ) -> Result<bool> {
         if let Cow::Owned(name) = normalize_native_name(&deck.name) {
             deck.name = name;
             deck.set_modified(usn);
         }
-        self.ensure_deck_name_unique(deck, usn)
+        self.ensure_deck_name_unique_with_policy(deck, usn, policy)
     }
 
     /// Add or update an existing deck modified by the user. May add parents,
@@ -287,33 +364,83 @@ impl Collection {
 
     /// Add a new deck. The id must be 0, as it will be automatically assigned.
     pub fn add_deck(&mut self, deck: &mut Deck) -> Result<OpOutput<()>> {
+        self.add_deck_with_policy(deck, DeckNameConflict::Suffix)
+    }
+
+    /// Like `add_deck`, but lets the caller choose what happens on a name
+    /// collision instead of always suffixing.
+    pub fn add_deck_with_policy(
+        &mut self,
+        deck: &mut Deck,
+        policy: DeckNameConflict,
+    ) -> Result<OpOutput<()>> {
         if deck.id.0 != 0 {
             return Err(AnkiError::invalid_input("deck to add must have id 0"));
         }
 
-        self.transact(Op::AddDeck, |col| col.add_deck_inner(deck, col.usn()?))
+        self.transact(Op::AddDeck, |col| {
+            let usn = col.usn()?;
+            col.add_deck_inner_with_policy(deck, usn, policy)
+        })
     }
 
     pub(crate) fn add_deck_inner(&mut self, deck: &mut Deck, usn: Usn) -> Result<()> {
-        self.prepare_deck_for_update(deck, usn)?;
+        self.add_deck_inner_with_policy(deck, usn, DeckNameConflict::Suffix)
+    }
+
+    pub(crate) fn add_deck_inner_with_policy(
+        &mut self,
+        deck: &mut Deck,
+        usn: Usn,
+        policy: DeckNameConflict,
+    ) -> Result<()> {
+        if self.prepare_deck_for_update_with_policy(deck, usn, policy)? {
+            // deck adopted the id of the colliding deck instead of being added
+            return Ok(());
+        }
         deck.set_modified(usn);
         self.match_or_create_parents(deck, usn)?;
-        self.add_deck_undoable(deck)
+        self.add_deck_undoable(deck)?;
+        self.deck_name_index()?.insert(&deck.name, deck.id);
+        Ok(())
     }
 
     pub fn update_deck(&mut self, deck: &mut Deck) -> Result<OpOutput<()>> {
+        self.update_deck_with_policy(deck, DeckNameConflict::Suffix)
+    }
+
+    /// Like `update_deck`, but lets the caller choose what happens on a name
+    /// collision instead of always suffixing.
+    pub fn update_deck_with_policy(
+        &mut self,
+        deck: &mut Deck,
+        policy: DeckNameConflict,
+    ) -> Result<OpOutput<()>> {
         self.transact(Op::UpdateDeck, |col| {
             let existing_deck = col.storage.get_deck(deck.id)?.ok_or(AnkiError::NotFound)?;
-            col.update_deck_inner(deck, existing_deck, col.usn()?)
+            let usn = col.usn()?;
+            col.update_deck_inner_with_policy(deck, existing_deck, usn, policy)
         })
     }
 
     pub fn rename_deck(&mut self, did: DeckID, new_human_name: &str) -> Result<OpOutput<()>> {
+        self.rename_deck_with_policy(did, new_human_name, DeckNameConflict::Suffix)
+    }
+
+    /// Like `rename_deck`, but lets the caller choose what happens on a name
+    /// collision instead of always suffixing.
+    pub fn rename_deck_with_policy(
+        &mut self,
+        did: DeckID,
+        new_human_name: &str,
+        policy: DeckNameConflict,
+    ) -> Result<OpOutput<()>> {
         self.transact(Op::RenameDeck, |col| {
             let existing_deck = col.storage.get_deck(did)?.ok_or(AnkiError::NotFound)?;
             let mut deck = existing_deck.clone();
             deck.name = human_deck_name_to_native(new_human_name);
-            col.update_deck_inner(&mut deck, existing_deck, col.usn()?)
+            let usn = col.usn()?;
+            col.update_deck_inner_with_policy(&mut deck, existing_deck, usn, policy)
         })
     }
 
@@ -323,7 +450,22 @@ impl Collection {
         original: Deck,
         usn: Usn,
     ) -> Result<()> {
-        self.prepare_deck_for_update(deck, usn)?;
+        self.update_deck_inner_with_policy(deck, original, usn, DeckNameConflict::Suffix)
+    }
+
+    pub(crate) fn update_deck_inner_with_policy(
+        &mut self,
+        deck: &mut Deck,
+        original: Deck,
+        usn: Usn,
+        policy: DeckNameConflict,
+    ) -> Result<()> {
+        if self.prepare_deck_for_update_with_policy(deck, usn, policy)? {
+            // deck was merged into the colliding deck and retired; the original
+            // deck no longer exists, so there's nothing left here to update
+            self.invalidate_deck_cache_for(Some(original.id));
+            return Ok(());
+        }
         deck.set_modified(usn);
         let name_changed = original.name != deck.name;
         if name_changed {
@@ -331,16 +473,94 @@ impl Collection {
             self.match_or_create_parents(deck, usn)?;
             // rename children
             self.rename_child_decks(&original, &deck.name, usn)?;
+            self.reindex_deck_rename(&original.name, &deck.name, deck.id)?;
         }
         self.update_single_deck_undoable(deck, original)?;
         if name_changed {
+            self.invalidate_deck_cache_for(Some(deck.id));
             // after updating, we need to ensure all grandparents exist, which may not be the case
-            // in the parent->child case
-            self.create_missing_parents(&deck.name, usn)?;
+            // in the parent->child case; this is the incremental variant so the
+            // newly-created-or-touched ancestors get their cache entries
+            // refreshed too, rather than clearing deck_cache wholesale
+            self.create_missing_parents_incremental(&deck.name, usn)?;
         }
         Ok(())
     }
 
+    /// Like `add_or_update_deck`, but lets the caller choose what happens on a
+    /// name collision instead of always suffixing.
+    pub(crate) fn add_or_update_deck_with_policy(
+        &mut self,
+        deck: &mut Deck,
+        policy: DeckNameConflict,
+    ) -> Result<OpOutput<()>> {
+        if deck.id.0 == 0 {
+            self.add_deck_with_policy(deck, policy)
+        } else {
+            self.update_deck_with_policy(deck, policy)
+        }
+    }
+
+    /// Three-way merges an imported collection's deck tree into this one,
+    /// rather than recreating names one at a time through
+    /// `get_or_create_normal_deck` (which produces `+`-suffixed duplicates
+    /// whenever both sides independently touched the same subtree).
+    ///
+    /// `base` should be the deck tree last seen by both sides (e.g. from the
+    /// last successful sync); pass an empty tree for a one-off import with
+    /// no shared history, which treats every deck that exists locally or in
+    /// `incoming` as freshly added. `incoming_touched` marks deck ids in
+    /// `incoming` that had cards added since `base`, so a deck deleted here
+    /// doesn't silently drop cards the other side added.
+    ///
+    /// Applies the merged tree through the existing
+    /// `add_or_update_single_deck_with_existing_id`/`remove_single_deck`
+    /// machinery, and returns any conflicts that were resolved but still
+    /// deserve the user's attention.
+    pub(crate) fn merge_imported_deck_tree(
+        &mut self,
+        base: &DeckTree,
+        incoming: &DeckTree,
+        incoming_touched: &HashSet<DeckID>,
+    ) -> Result<Vec<DeckMergeConflict>> {
+        let mut local = DeckTree::new();
+        for (did, _human_name) in self.storage.get_all_deck_names()? {
+            if let Some(deck) = self.storage.get_deck(did)? {
+                local.insert(did, deck.name);
+            }
+        }
+
+        let (renames, deletions, conflicts) =
+            merge_deck_trees(base, &local, incoming, &HashSet::new(), incoming_touched);
+
+        self.transact_no_undo(|col| {
+            let usn = col.usn()?;
+            for (did, name) in renames {
+                let mut deck = col.storage.get_deck(did)?.unwrap_or_else(|| {
+                    let mut deck = Deck::new_normal();
+                    deck.id = did;
+                    deck
+                });
+                deck.name = name;
+                col.add_or_update_single_deck_with_existing_id_and_policy(
+                    &mut deck,
+                    usn,
+                    DeckNameConflict::Merge,
+                )?;
+            }
+
+            for did in &deletions {
+                if let Some(deck) = col.storage.get_deck(*did)? {
+                    col.remove_single_deck(&deck, usn)?;
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(conflicts)
+    }
+
     /// Add/update a single deck when syncing/importing. Ensures name is unique
     /// & normalized, but does not check parents/children or update mtime
     /// (unless the name was changed). Caller must set up transaction.
@@ -349,23 +569,110 @@ impl Collection {
         deck: &mut Deck,
         usn: Usn,
     ) -> Result<()> {
-        self.prepare_deck_for_update(deck, usn)?;
-        self.add_or_update_deck_with_existing_id_undoable(deck)
+        self.add_or_update_single_deck_with_existing_id_and_policy(
+            deck,
+            usn,
+            DeckNameConflict::Suffix,
+        )
+    }
+
+    /// Like `add_or_update_single_deck_with_existing_id`, but lets the
+    /// caller choose what happens on a name collision instead of always
+    /// suffixing.
+    pub(crate) fn add_or_update_single_deck_with_existing_id_and_policy(
+        &mut self,
+        deck: &mut Deck,
+        usn: Usn,
+        policy: DeckNameConflict,
+    ) -> Result<()> {
+        let old_name = self.storage.get_deck(deck.id)?.map(|d| d.name);
+        if self.prepare_deck_for_update_with_policy(deck, usn, policy)? {
+            // deck was merged into (or adopted the id of) the colliding deck;
+            // merging already took care of its own index bookkeeping
+            return Ok(());
+        }
+        self.add_or_update_deck_with_existing_id_undoable(deck)?;
+        if let Some(old_name) = &old_name {
+            self.deck_name_index()?.remove(old_name);
+        }
+        self.deck_name_index()?.insert(&deck.name, deck.id);
+        Ok(())
     }
 
-    pub(crate) fn ensure_deck_name_unique(&self, deck: &mut Deck, usn: Usn) -> Result<()> {
+    pub(crate) fn ensure_deck_name_unique(&mut self, deck: &mut Deck, usn: Usn) -> Result<()> {
+        self.ensure_deck_name_unique_with_policy(deck, usn, DeckNameConflict::Suffix)
+            .map(|_merged| ())
+    }
+
+    /// Like `ensure_deck_name_unique`, but lets the caller choose how a
+    /// collision is resolved. Returns `true` if `deck` was merged into the
+    /// existing deck at that name and retired - the caller must not persist
+    /// `deck` under its own id any further in that case.
+    pub(crate) fn ensure_deck_name_unique_with_policy(
+        &mut self,
+        deck: &mut Deck,
+        usn: Usn,
+        policy: DeckNameConflict,
+    ) -> Result<bool> {
         loop {
             match self.storage.get_deck_id(&deck.name)? {
                 Some(did) if did == deck.id => {
                     break;
                 }
                 None => break,
-                _ => (),
+                Some(existing_did) => match policy {
+                    DeckNameConflict::Suffix => {
+                        deck.name += "+";
+                        deck.set_modified(usn);
+                        continue;
+                    }
+                    DeckNameConflict::Fail => {
+                        return Err(AnkiError::invalid_input(format!(
+                            "deck name '{}' already in use",
+                            deck.human_name()
+                        )));
+                    }
+                    DeckNameConflict::Merge => {
+                        if self.storage.get_deck(deck.id)?.is_none() {
+                            // the incoming deck hasn't been persisted yet (it's
+                            // either unassigned or newly arriving with a
+                            // preset id from an import), so there's nothing
+                            // of its own to move - just adopt the existing id
+                            deck.id = existing_did;
+                        } else {
+                            self.merge_deck_into(deck.id, existing_did, usn)?;
+                        }
+                        return Ok(true);
+                    }
+                },
             }
-            deck.name += "+";
-            deck.set_modified(usn);
         }
 
+        Ok(false)
+    }
+
+    /// Moves every card directly in `source` into `target`, reparents
+    /// `source`'s child decks under `target`, and retires `source` (as when
+    /// the user drags one deck onto another that shares a subtree).
+    fn merge_deck_into(&mut self, source: DeckID, target: DeckID, usn: Usn) -> Result<()> {
+        let source_deck = self.storage.get_deck(source)?.ok_or(AnkiError::NotFound)?;
+        let target_deck = self.storage.get_deck(target)?.ok_or(AnkiError::NotFound)?;
+
+        let cids = self.storage.all_cards_in_single_deck(source)?;
+        self.storage.set_search_table_to_card_ids(&cids, false)?;
+        for original in self.storage.all_searched_cards()? {
+            let mut card = original.clone();
+            card.did = target;
+            self.update_card_inner(&mut card, original, usn)?;
+        }
+        self.storage.clear_searched_cards_table()?;
+
+        self.rename_child_decks(&source_deck, &target_deck.name, usn)?;
+
+        self.clear_aux_config_for_deck(source)?;
+        self.deck_name_index()?.remove(&source_deck.name);
+        self.remove_deck_and_add_grave_undoable(source_deck, usn)?;
+
         Ok(())
     }
 
@@ -390,29 +697,65 @@ impl Collection {
     }
 
     fn rename_child_decks(&mut self, old: &Deck, new_name: &str, usn: Usn) -> Result<()> {
-        let children = self.storage.child_decks(old)?;
+        // resolved via the name index instead of `storage.child_decks`, so a
+        // large subtree rename costs one in-memory subtree walk rather than
+        // a storage round trip per descendant
+        let child_ids: Vec<DeckID> = self
+            .deck_name_index()?
+            .descendants(&old.name)
+            .into_iter()
+            .filter(|did| *did != old.id)
+            .collect();
+        let mut children = Vec::with_capacity(child_ids.len());
+        for did in child_ids {
+            if let Some(child) = self.storage.get_deck(did)? {
+                children.push(child);
+            }
+        }
+
         let old_component_count = old.name.matches('\x1f').count() + 1;
+        let mut changed = Vec::with_capacity(children.len());
 
         for mut child in children {
             let original = child.clone();
             let child_components: Vec<_> = child.name.split('\x1f').collect();
             let child_only = &child_components[old_component_count..];
             let new_name = format!("{}\x1f{}", new_name, child_only.join("\x1f"));
+            self.reindex_deck_rename(&child.name, &new_name, child.id)?;
             child.name = new_name;
             child.set_modified(usn);
+            changed.push(child.id);
             self.update_single_deck_undoable(&mut child, original)?;
         }
 
+        // only the decks we actually touched need their cache entry
+        // refreshed - the rest of a large subtree rename is left untouched,
+        // avoiding a full deck_cache rebuild
+        self.invalidate_deck_cache_for(changed);
+
         Ok(())
     }
 
+    /// Evicts exactly the given deck ids from `state.deck_cache`, instead of
+    /// clearing the whole cache the way a bulk rename otherwise would. The
+    /// next `get_deck()` for an evicted id simply re-reads it from storage,
+    /// so this stays correct even when the caller only knows the changed
+    /// set, not every id that might be cached.
+    fn invalidate_deck_cache_for(&mut self, dids: impl IntoIterator<Item = DeckID>) {
+        for did in dids {
+            self.state.deck_cache.remove(&did);
+        }
+    }
+
     /// Add a single, normal deck with the provided name for a child deck.
     /// Caller must have done necessarily validation on name.
     fn add_parent_deck(&mut self, machine_name: &str, usn: Usn) -> Result<()> {
         let mut deck = Deck::new_normal();
         deck.name = machine_name.into();
         deck.set_modified(usn);
-        self.add_deck_undoable(&mut deck)
+        self.add_deck_undoable(&mut deck)?;
+        self.deck_name_index()?.insert(&deck.name, deck.id);
+        Ok(())
     }
 
     /// If parent deck(s) exist, rewrite name to match their case.
@@ -446,7 +789,7 @@ impl Collection {
 
     fn create_missing_parents(&mut self, mut machine_name: &str, usn: Usn) -> Result<()> {
         while let Some(parent_name) = immediate_parent_name(machine_name) {
-            if self.storage.get_deck_id(parent_name)?.is_none() {
+            if self.deck_name_index()?.get(parent_name).is_none() {
                 self.add_parent_deck(parent_name, usn)?;
             }
             machine_name = parent_name;
@@ -454,8 +797,32 @@ impl Collection {
         Ok(())
     }
 
+    /// Like `create_missing_parents`, but also evicts any already-cached
+    /// ancestor whose name the new deck renders stale (e.g. an ancestor that
+    /// existed before a `match_or_create_parents()` case rewrite, but under
+    /// different casing). Used after a bulk rename, where the affected
+    /// strata include not just the renamed decks but any ancestor the rename
+    /// newly created or touched.
+    fn create_missing_parents_incremental(&mut self, mut machine_name: &str, usn: Usn) -> Result<()> {
+        let mut touched = Vec::new();
+        while let Some(parent_name) = immediate_parent_name(machine_name) {
+            match self.deck_name_index()?.get(parent_name) {
+                None => {
+                    self.add_parent_deck(parent_name, usn)?;
+                    if let Some(did) = self.deck_name_index()?.get(parent_name) {
+                        touched.push(did);
+                    }
+                }
+                Some(did) => touched.push(did),
+            }
+            machine_name = parent_name;
+        }
+        self.invalidate_deck_cache_for(touched);
+        Ok(())
+    }
+
     fn first_existing_parent(
-        &self,
+        &mut self,
         machine_name: &str,
         recursion_level: usize,
     ) -> Result<Option<Deck>> {
@@ -463,7 +830,7 @@ impl Collection {
             return Err(AnkiError::invalid_input("deck nesting level too deep"));
         }
         if let Some(parent_name) = immediate_parent_name(machine_name) {
-            if let Some(parent_did) = self.storage.get_deck_id(parent_name)? {
+            if let Some(parent_did) = self.deck_name_index()?.get(parent_name) {
                 self.storage.get_deck(parent_did)
             } else {
                 self.first_existing_parent(parent_name, recursion_level + 1)
@@ -486,7 +853,22 @@ impl Collection {
             let usn = col.usn()?;
             for did in dids {
                 if let Some(deck) = col.storage.get_deck(*did)? {
-                    let child_decks = col.storage.child_decks(&deck)?;
+                    // resolved via the name index instead of
+                    // `storage.child_decks`, so a large subtree removal
+                    // costs one in-memory subtree walk rather than a
+                    // storage round trip per descendant
+                    let child_ids: Vec<DeckID> = col
+                        .deck_name_index()?
+                        .descendants(&deck.name)
+                        .into_iter()
+                        .filter(|child_did| *child_did != deck.id)
+                        .collect();
+                    let mut child_decks = Vec::with_capacity(child_ids.len());
+                    for child_did in child_ids {
+                        if let Some(child) = col.storage.get_deck(child_did)? {
+                            child_decks.push(child);
+                        }
+                    }
 
                     // top level
                     card_count += col.remove_single_deck(&deck, usn)?;
@@ -511,12 +893,15 @@ impl Collection {
         };
         self.clear_aux_config_for_deck(deck.id)?;
         if deck.id.0 == 1 {
-            // if deleting the default deck, ensure there's a new one, and avoid the grave
+            // if deleting the default deck, ensure there's a new one, and
+            // avoid the grave; add_or_update_single_deck_with_existing_id
+            // takes care of re-indexing the rename itself
             let mut deck = deck.to_owned();
             deck.name = self.i18n.tr(TR::DeckConfigDefaultName).into();
             deck.set_modified(usn);
             self.add_or_update_single_deck_with_existing_id(&mut deck, usn)?;
         } else {
+            self.deck_name_index()?.remove(&deck.name);
             self.remove_deck_and_add_grave_undoable(deck.clone(), usn)?;
         }
         Ok(card_count)
@@ -562,14 +947,16 @@ impl Collection {
         input: pb::UpdateStatsIn,
     ) -> Result<()> {
         let did = input.deck_id.into();
+        if self.storage.get_deck(did)?.is_none() {
+            return Ok(());
+        }
         let mutator = |c: &mut DeckCommon| {
             c.new_studied += input.new_delta;
             c.review_studied += input.review_delta;
             c.milliseconds_studied += input.millisecond_delta;
         };
-        if let Some(mut deck) = self.storage.get_deck(did)? {
-            self.update_deck_stats_single(today, usn, &mut deck, mutator)?;
-            for mut deck in self.storage.parent_decks(&deck)? {
+        for affected in self.decks_on_ancestor_path(did)? {
+            if let Some(mut deck) = self.storage.get_deck(affected)? {
                 self.update_deck_stats_single(today, usn, &mut deck, mutator)?;
             }
         }
@@ -587,16 +974,17 @@ impl Collection {
         new_delta: i32,
         review_delta: i32,
     ) -> Result<()> {
+        if self.storage.get_deck(did)?.is_none() {
+            return Ok(());
+        }
         let mutator = |c: &mut DeckCommon| {
             c.new_studied -= new_delta;
             c.review_studied -= review_delta;
         };
-        if let Some(mut deck) = self.storage.get_deck(did)? {
-            self.update_deck_stats_single(today, usn, &mut deck, mutator)?;
-            for mut deck in self.storage.parent_decks(&deck)? {
-                self.update_deck_stats_single(today, usn, &mut deck, mutator)?;
-            }
-            for mut deck in self.storage.child_decks(&deck)? {
+        let mut affected = self.decks_on_ancestor_path(did)?;
+        affected.extend(self.decks_in_subtree(did)?);
+        for affected in affected {
+            if let Some(mut deck) = self.storage.get_deck(affected)? {
                 self.update_deck_stats_single(today, usn, &mut deck, mutator)?;
             }
         }
@@ -604,6 +992,62 @@ impl Collection {
         Ok(())
     }
 
+    /// Fetches every deck in one pass, for callers that need to walk the
+    /// whole tree in memory rather than issuing a storage round trip per
+    /// ancestor/descendant.
+    fn fetch_all_decks(&mut self) -> Result<Vec<Deck>> {
+        let all_names = self.storage.get_all_deck_names()?;
+        let mut decks = Vec::with_capacity(all_names.len());
+        for (did, _human_name) in &all_names {
+            if let Some(deck) = self.storage.get_deck(*did)? {
+                decks.push(deck);
+            }
+        }
+        Ok(decks)
+    }
+
+    /// `did` and every one of its ancestors, resolved via `aggregate_subtrees`
+    /// rather than the old one-ancestor-at-a-time `storage.parent_decks`
+    /// walk: a leaf value of `1` is placed on `did` alone, and since `Sum`
+    /// rolls a deck's own value into every ancestor's subtree total, the
+    /// decks whose resulting total is non-zero are exactly `did` and its
+    /// ancestors.
+    fn decks_on_ancestor_path(&mut self, did: DeckID) -> Result<Vec<DeckID>> {
+        let decks = self.fetch_all_decks()?;
+        let leaves: Vec<(DeckID, String, i64)> = decks
+            .into_iter()
+            .map(|deck| {
+                let own = if deck.id == did { 1 } else { 0 };
+                (deck.id, deck.name, own)
+            })
+            .collect();
+        let aggs = aggregate_subtrees(&leaves, AggrKind::Sum);
+        Ok(aggs
+            .into_iter()
+            .filter(|(_, agg)| agg.subtree != 0)
+            .map(|(did, _)| did)
+            .collect())
+    }
+
+    /// Every descendant of `did`, found by a name-prefix check over the same
+    /// single `fetch_all_decks` pass `decks_on_ancestor_path` uses. This
+    /// isn't something `aggregate_subtrees` itself models (it only rolls
+    /// values up towards the root), so it's a plain in-memory filter instead
+    /// of the old one-call-per-level `storage.child_decks` walk.
+    fn decks_in_subtree(&mut self, did: DeckID) -> Result<Vec<DeckID>> {
+        let decks = self.fetch_all_decks()?;
+        let root_name = match decks.iter().find(|deck| deck.id == did) {
+            Some(deck) => deck.name.clone(),
+            None => return Ok(Vec::new()),
+        };
+        let prefix = format!("{}\x1f", root_name);
+        Ok(decks
+            .into_iter()
+            .filter(|deck| deck.id != did && deck.name.starts_with(&prefix))
+            .map(|deck| deck.id)
+            .collect())
+    }
+
     pub(crate) fn counts_for_deck_today(
         &mut self,
         did: DeckID,
@@ -638,9 +1082,21 @@ impl Collection {
         &mut self,
         deck_ids: &[DeckID],
         new_parent: Option<DeckID>,
+    ) -> Result<OpOutput<usize>> {
+        self.reparent_decks_with_policy(deck_ids, new_parent, DeckNameConflict::Suffix)
+    }
+
+    /// Like `reparent_decks`, but lets the caller choose what happens when the
+    /// dragged deck's new name collides with an existing deck, instead of
+    /// always suffixing.
+    pub fn reparent_decks_with_policy(
+        &mut self,
+        deck_ids: &[DeckID],
+        new_parent: Option<DeckID>,
+        policy: DeckNameConflict,
     ) -> Result<OpOutput<usize>> {
         self.transact(Op::ReparentDeck, |col| {
-            col.reparent_decks_inner(deck_ids, new_parent)
+            col.reparent_decks_inner(deck_ids, new_parent, policy)
         })
     }
 
@@ -648,6 +1104,7 @@ impl Collection {
         &mut self,
         deck_ids: &[DeckID],
         new_parent: Option<DeckID>,
+        policy: DeckNameConflict,
     ) -> Result<usize> {
         let usn = self.usn()?;
         let target_deck;
@@ -663,6 +1120,7 @@ impl Collection {
         }
 
         let mut count = 0;
+        let mut changed = Vec::new();
         for deck in deck_ids {
             if let Some(mut deck) = self.storage.get_deck(*deck)? {
                 if let Some(new_name) = reparented_name(&deck.name, target_name) {
@@ -677,26 +1135,160 @@ impl Collection {
 
                     deck.set_modified(usn);
                     deck.name = new_name;
-                    self.ensure_deck_name_unique(&mut deck, usn)?;
+                    if self.ensure_deck_name_unique_with_policy(&mut deck, usn, policy)? {
+                        // merged into the colliding deck and retired; its
+                        // grandchildren were reparented as part of the merge
+                        changed.push(orig.id);
+                        continue;
+                    }
                     self.rename_child_decks(&orig, &deck.name, usn)?;
+                    self.reindex_deck_rename(&orig.name, &deck.name, deck.id)?;
+                    changed.push(deck.id);
                     self.update_single_deck_undoable(&mut deck, orig)?;
                 }
             }
         }
+        // strata: the decks we directly reparented, plus whatever
+        // rename_child_decks already evicted for their descendants
+        self.invalidate_deck_cache_for(changed);
 
         Ok(count)
     }
+
+    /// Reparents many decks at once, each to its own independent new
+    /// parent, validating the whole batch for cycles against a single
+    /// throwaway `forest::DeckForest` before applying any of it. Unlike
+    /// `reparent_decks` (which drops a set of decks onto one common target
+    /// and checks each drop in isolation via name prefixes), this lets the
+    /// GUI commit an arbitrary tree reorganization atomically - if any move
+    /// in the batch would create a cycle, none of them are applied.
+    pub fn reparent_decks_with_moves(
+        &mut self,
+        moves: &[(DeckID, Option<DeckID>)],
+    ) -> Result<OpOutput<usize>> {
+        self.transact(Op::ReparentDeck, |col| {
+            col.reparent_decks_with_moves_inner(moves)
+        })
+    }
+
+    pub(crate) fn reparent_decks_with_moves_inner(
+        &mut self,
+        moves: &[(DeckID, Option<DeckID>)],
+    ) -> Result<usize> {
+        let usn = self.usn()?;
+        let mut decks = Vec::new();
+        for (did, _human_name) in self.storage.get_all_deck_names()? {
+            if let Some(deck) = self.storage.get_deck(did)? {
+                decks.push((did, deck.name));
+            }
+        }
+
+        let renames = reparent_decks_batch(&decks, moves).map_err(|e| match e {
+            DeckForestError::UnknownDeck(did) | DeckForestError::UnknownParent(did) => {
+                AnkiError::invalid_input(format!("deck {} does not exist", did))
+            }
+            DeckForestError::Cycle(did) => AnkiError::invalid_input(format!(
+                "cannot move deck {} onto itself or one of its own children",
+                did
+            )),
+        })?;
+
+        let mut changed = Vec::with_capacity(renames.len());
+        for (did, name) in renames {
+            if let Some(mut deck) = self.storage.get_deck(did)? {
+                let original = deck.clone();
+                self.reindex_deck_rename(&original.name, &name, did)?;
+                deck.name = name;
+                deck.set_modified(usn);
+                self.update_single_deck_undoable(&mut deck, original)?;
+                changed.push(did);
+            }
+        }
+        let count = changed.len();
+        self.invalidate_deck_cache_for(changed);
+
+        Ok(count)
+    }
+
+    /// Moves a single deck to `new_parent`, inserting it at `position`
+    /// among its new siblings instead of always landing at the end like
+    /// `reparent_decks`/`reparent_decks_with_moves` do. Renaming a parent
+    /// only ever rewrites its descendants' names via `rename_child_decks`
+    /// and never touches sibling positions, so the relative order a
+    /// subtree was given here survives any later rename.
+    pub fn move_deck(
+        &mut self,
+        did: DeckID,
+        new_parent: Option<DeckID>,
+        position: SiblingPosition,
+    ) -> Result<OpOutput<()>> {
+        self.transact(Op::ReparentDeck, |col| {
+            col.move_deck_inner(did, new_parent, position)
+        })
+    }
+
+    pub(crate) fn move_deck_inner(
+        &mut self,
+        did: DeckID,
+        new_parent: Option<DeckID>,
+        position: SiblingPosition,
+    ) -> Result<()> {
+        let usn = self.usn()?;
+        let mut decks = Vec::new();
+        let mut ordinals = Vec::new();
+        for (id, _human_name) in self.storage.get_all_deck_names()? {
+            if let Some(deck) = self.storage.get_deck(id)? {
+                ordinals.push((id, self.storage.get_deck_ordinal(id)?));
+                decks.push((id, deck.name));
+            }
+        }
+
+        let (renames, new_ordinals) =
+            move_deck_to_position(&decks, &ordinals, did, new_parent, position).map_err(|e| {
+                match e {
+                    DeckForestError::UnknownDeck(did) | DeckForestError::UnknownParent(did) => {
+                        AnkiError::invalid_input(format!("deck {} does not exist", did))
+                    }
+                    DeckForestError::Cycle(did) => AnkiError::invalid_input(format!(
+                        "cannot move deck {} onto itself or one of its own children",
+                        did
+                    )),
+                }
+            })?;
+
+        let mut changed = Vec::with_capacity(renames.len());
+        for (did, name) in renames {
+            if let Some(mut deck) = self.storage.get_deck(did)? {
+                let original = deck.clone();
+                self.reindex_deck_rename(&original.name, &name, did)?;
+                deck.name = name;
+                deck.set_modified(usn);
+                self.update_single_deck_undoable(&mut deck, original)?;
+                changed.push(did);
+            }
+        }
+        for (did, ordinal) in new_ordinals {
+            self.storage.set_deck_ordinal(did, ordinal)?;
+        }
+        self.invalidate_deck_cache_for(changed);
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{human_deck_name_to_native, immediate_parent_name, normalize_native_name};
+    use super::{
+        human_deck_name_to_native, immediate_parent_name, normalize_native_name, DeckID,
+        DeckNameConflict, DeckTree, SiblingPosition,
+    };
     use crate::decks::reparented_name;
     use crate::{
         collection::{open_test_collection, Collection},
         err::Result,
         search::SortMode,
     };
+    use std::collections::HashSet;
 
     fn sorted_names(col: &Collection) -> Vec<String> {
         col.storage
@@ -823,6 +1415,111 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn merge_policy() -> Result<()> {
+        let mut col = open_test_collection();
+
+        let _ = col.get_or_create_normal_deck("other::foo")?;
+        let mut middle = col.get_or_create_normal_deck("quux::foo")?;
+        let _ = col.get_or_create_normal_deck("quux::foo::baz")?;
+
+        // the Fail policy rejects the collision instead of suffixing
+        middle.name = "other\x1ffoo".into();
+        col.add_or_update_deck_with_policy(&mut middle, DeckNameConflict::Fail)
+            .unwrap_err();
+
+        // merging quux::foo into other::foo should move its cards and
+        // reparent its children (e.g. quux::foo::baz -> other::foo::baz)
+        col.add_or_update_deck_with_policy(&mut middle, DeckNameConflict::Merge)?;
+        assert_eq!(
+            sorted_names(&col),
+            vec!["Default", "other", "other::foo", "other::foo::baz", "quux"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_merge() -> Result<()> {
+        let mut col = open_test_collection();
+
+        let foo = col.get_or_create_normal_deck("foo")?;
+        let _bar = col.get_or_create_normal_deck("bar")?;
+
+        // base reflects the tree as both sides last saw it
+        let base: DeckTree = vec![(foo.id, "foo".to_string())].into_iter().collect();
+
+        // locally, foo was renamed since base
+        let mut renamed_foo = foo.clone();
+        renamed_foo.name = "foo2".into();
+        col.add_or_update_deck(&mut renamed_foo)?;
+
+        // the incoming collection left foo alone, but added a new deck
+        let new_id = DeckID(999_999_999);
+        let incoming: DeckTree = vec![(foo.id, "foo".to_string()), (new_id, "baz".to_string())]
+            .into_iter()
+            .collect();
+
+        let conflicts = col.merge_imported_deck_tree(&base, &incoming, &HashSet::new())?;
+        assert!(conflicts.is_empty());
+        assert_eq!(sorted_names(&col), vec!["Default", "bar", "baz", "foo2"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reparent_batch() -> Result<()> {
+        let mut col = open_test_collection();
+
+        let foo = col.get_or_create_normal_deck("foo")?;
+        let _foo_bar = col.get_or_create_normal_deck("foo::bar")?;
+        let baz = col.get_or_create_normal_deck("baz")?;
+
+        // move foo (with its child bar) under baz in one batch
+        col.reparent_decks_with_moves(&[(foo.id, Some(baz.id))])?;
+        assert_eq!(
+            sorted_names(&col),
+            vec!["Default", "baz", "baz::foo", "baz::foo::bar"]
+        );
+
+        // a batch that would drop baz onto its own new child is rejected
+        // in full, leaving the tree untouched
+        col.reparent_decks_with_moves(&[(baz.id, Some(foo.id))])
+            .unwrap_err();
+        assert_eq!(
+            sorted_names(&col),
+            vec!["Default", "baz", "baz::foo", "baz::foo::bar"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn move_into_position() -> Result<()> {
+        let mut col = open_test_collection();
+
+        let foo = col.get_or_create_normal_deck("foo")?;
+        let a = col.get_or_create_normal_deck("foo::a")?;
+        let b = col.get_or_create_normal_deck("foo::b")?;
+        let c = col.get_or_create_normal_deck("foo::c")?;
+
+        // c was created last, so it's currently foo's third child; move it
+        // to land between a and b instead
+        col.move_deck(c.id, Some(foo.id), SiblingPosition::After(a.id))?;
+
+        let ordinal = |col: &mut Collection, did: DeckID| col.storage.get_deck_ordinal(did).unwrap();
+        assert_eq!(ordinal(&mut col, a.id), 0);
+        assert_eq!(ordinal(&mut col, c.id), 1);
+        assert_eq!(ordinal(&mut col, b.id), 2);
+
+        // moving foo onto its own child is rejected, same as the plain
+        // reparent API
+        col.move_deck(foo.id, Some(c.id), SiblingPosition::Append)
+            .unwrap_err();
+
+        Ok(())
+    }
+
     #[test]
     fn default() -> Result<()> {
         // deleting the default deck will remove cards, but bring the deck back
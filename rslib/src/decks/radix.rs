@@ -0,0 +1,242 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! An in-memory radix (patricia) index over native deck names, keyed on
+//! `\x1f`-separated path components rather than raw bytes. Each edge holds a
+//! compressed run of one or more whole components, and leaves/branches carry
+//! the `DeckId` of the deck at that path, if any. This gives O(path-length)
+//! lookup and O(subtree-size) descendant enumeration, replacing the full
+//! prefix scans that `immediate_parent_name`/child-renaming otherwise need.
+//!
+//! Compressing by whole component (rather than by byte, as a classic radix
+//! tree would) is what keeps `foo` and `foobar` from ever being confused for
+//! one being a prefix of the other: they only share an edge if they share a
+//! complete leading *component*, e.g. `foo\x1fbar` and `foo\x1fbaz` share the
+//! `foo` edge, but `foo` and `foobar` never do.
+
+use std::mem;
+
+use super::DeckID;
+
+#[derive(Debug, Default)]
+pub(crate) struct DeckNameIndex {
+    root: Node,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// the compressed run of components this edge represents; empty only
+    /// for the root
+    label: Vec<String>,
+    did: Option<DeckID>,
+    children: Vec<Node>,
+}
+
+impl DeckNameIndex {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuilds the index from scratch given every (native name, id) pair,
+    /// as when loading the index fresh from the DB.
+    pub(crate) fn rebuild(entries: impl IntoIterator<Item = (String, DeckID)>) -> Self {
+        let mut index = Self::new();
+        for (name, did) in entries {
+            index.insert(&name, did);
+        }
+        index
+    }
+
+    pub(crate) fn insert(&mut self, native_name: &str, did: DeckID) {
+        let comps: Vec<String> = native_name.split('\x1f').map(Into::into).collect();
+        self.root.insert(&comps, did);
+    }
+
+    pub(crate) fn remove(&mut self, native_name: &str) {
+        let comps: Vec<String> = native_name.split('\x1f').map(Into::into).collect();
+        self.root.remove(&comps);
+    }
+
+    pub(crate) fn get(&self, native_name: &str) -> Option<DeckID> {
+        let comps: Vec<String> = native_name.split('\x1f').map(Into::into).collect();
+        self.root.get(&comps)
+    }
+
+    /// Every deck id whose native name is `native_name` itself or a
+    /// descendant of it (`native_name\x1f...`).
+    pub(crate) fn descendants(&self, native_name: &str) -> Vec<DeckID> {
+        let comps: Vec<String> = native_name.split('\x1f').map(Into::into).collect();
+        let mut out = Vec::new();
+        if let Some(subtree_root) = self.root.find_subtree(&comps) {
+            subtree_root.collect_dids(&mut out);
+        }
+        out
+    }
+}
+
+impl Node {
+    fn insert(&mut self, comps: &[String], did: DeckID) {
+        if comps.is_empty() {
+            self.did = Some(did);
+            return;
+        }
+        // does an existing child share a leading component with `comps`?
+        if let Some(child) = self
+            .children
+            .iter_mut()
+            .find(|c| c.label.first() == comps.first())
+        {
+            let shared = common_prefix_len(&child.label, comps);
+            if shared == child.label.len() {
+                // the whole edge is shared; recurse past it
+                child.insert(&comps[shared..], did);
+            } else {
+                // diverges partway through the edge; split it into a branch
+                // node (the shared prefix, kept in place on `child`) with
+                // the old tail demoted to a new child of it
+                let displaced = Node {
+                    label: child.label.split_off(shared),
+                    did: child.did.take(),
+                    children: mem::take(&mut child.children),
+                };
+                child.children.push(displaced);
+                child.insert(&comps[shared..], did);
+            }
+            return;
+        }
+        // no shared edge; add a brand new one
+        self.children.push(Node {
+            label: comps.to_vec(),
+            did: Some(did),
+            children: vec![],
+        });
+    }
+
+    fn remove(&mut self, comps: &[String]) {
+        if comps.is_empty() {
+            self.did = None;
+            return;
+        }
+        if let Some(idx) = self
+            .children
+            .iter()
+            .position(|c| comps.len() >= c.label.len() && c.label[..] == comps[..c.label.len()])
+        {
+            let child = &mut self.children[idx];
+            child.remove(&comps[child.label.len()..]);
+            if child.did.is_none() && child.children.is_empty() {
+                self.children.remove(idx);
+            } else if child.did.is_none() && child.children.len() == 1 {
+                // collapse a now-redundant branch node back into a single edge
+                let mut only_child = child.children.pop().unwrap();
+                let mut merged_label = mem::take(&mut child.label);
+                merged_label.append(&mut only_child.label);
+                only_child.label = merged_label;
+                self.children[idx] = only_child;
+            }
+        }
+    }
+
+    fn get(&self, comps: &[String]) -> Option<DeckID> {
+        if comps.is_empty() {
+            return self.did;
+        }
+        self.children
+            .iter()
+            .find(|c| comps.len() >= c.label.len() && c.label[..] == comps[..c.label.len()])
+            .and_then(|c| c.get(&comps[c.label.len()..]))
+    }
+
+    /// Finds the node whose accumulated path equals `comps` or, when `comps`
+    /// ends partway along a compressed edge, the node that edge leads to -
+    /// either way, its whole subtree is exactly the decks under `comps`.
+    fn find_subtree(&self, comps: &[String]) -> Option<&Node> {
+        if comps.is_empty() {
+            return Some(self);
+        }
+        let child = self.children.iter().find(|c| {
+            let shared = comps.len().min(c.label.len());
+            c.label[..shared] == comps[..shared]
+        })?;
+        if comps.len() < child.label.len() {
+            Some(child)
+        } else {
+            child.find_subtree(&comps[child.label.len()..])
+        }
+    }
+
+    fn collect_dids(&self, out: &mut Vec<DeckID>) {
+        if let Some(did) = self.did {
+            out.push(did);
+        }
+        for child in &self.children {
+            child.collect_dids(out);
+        }
+    }
+}
+
+fn common_prefix_len(a: &[String], b: &[String]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn whole_component_labels_dont_collide_on_byte_prefix() {
+        let mut idx = DeckNameIndex::new();
+        idx.insert("foo", DeckID(1));
+        idx.insert("foobar", DeckID(2));
+        assert_eq!(idx.get("foo"), Some(DeckID(1)));
+        assert_eq!(idx.get("foobar"), Some(DeckID(2)));
+        // neither is in the other's subtree, despite sharing a byte prefix
+        assert_eq!(idx.descendants("foo"), vec![DeckID(1)]);
+        assert_eq!(idx.descendants("foobar"), vec![DeckID(2)]);
+    }
+
+    #[test]
+    fn finds_exact_parent_despite_label_compression() {
+        let mut idx = DeckNameIndex::new();
+        idx.insert("foo\x1fbar\x1fbaz", DeckID(1));
+        // "foo" and "foo\x1fbar" are implicit (no deck), compressed into one
+        // edge down to "baz" - but an exact lookup for either must still
+        // come back empty, not accidentally match the leaf
+        assert_eq!(idx.get("foo"), None);
+        assert_eq!(idx.get("foo\x1fbar"), None);
+        assert_eq!(idx.get("foo\x1fbar\x1fbaz"), Some(DeckID(1)));
+    }
+
+    #[test]
+    fn descendants_enumerates_whole_subtree() {
+        let mut idx = DeckNameIndex::new();
+        idx.insert("foo", DeckID(1));
+        idx.insert("foo\x1fbar", DeckID(2));
+        idx.insert("foo\x1fbar\x1fbaz", DeckID(3));
+        idx.insert("foo\x1fqux", DeckID(4));
+
+        let mut ids = idx.descendants("foo\x1fbar");
+        ids.sort_unstable_by_key(|d| d.0);
+        assert_eq!(ids, vec![DeckID(2), DeckID(3)]);
+    }
+
+    #[test]
+    fn splitting_an_edge_preserves_both_branches() {
+        let mut idx = DeckNameIndex::new();
+        idx.insert("foo\x1fbar", DeckID(1));
+        // forces the foo\x1fbar edge to split after "foo"
+        idx.insert("foo\x1fbaz", DeckID(2));
+        assert_eq!(idx.get("foo\x1fbar"), Some(DeckID(1)));
+        assert_eq!(idx.get("foo\x1fbaz"), Some(DeckID(2)));
+    }
+
+    #[test]
+    fn remove_collapses_redundant_branch() {
+        let mut idx = DeckNameIndex::new();
+        idx.insert("foo\x1fbar", DeckID(1));
+        idx.insert("foo\x1fbar\x1fbaz", DeckID(2));
+        idx.remove("foo\x1fbar");
+        assert_eq!(idx.get("foo\x1fbar"), None);
+        assert_eq!(idx.get("foo\x1fbar\x1fbaz"), Some(DeckID(2)));
+    }
+}
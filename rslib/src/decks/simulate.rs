@@ -0,0 +1,308 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Monte Carlo projection of future study load. Given the current
+//! distribution of due cards and the deck limits in force, repeatedly
+//! simulates the next few days of study so a user can see roughly how many
+//! reviews/day their current limits will produce, e.g. "if I keep these
+//! limits, my reviews will spike to ~40/day next week."
+//!
+//! Each trial independently walks the deck tree top-down, day by day,
+//! distributing the remaining new/review allowance and drawing pass/fail
+//! for each simulated review. Results across all trials are accumulated per
+//! (deck, future day) so the caller can report a mean and percentiles rather
+//! than a single noisy run.
+
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use super::DeckID;
+use crate::prelude::*;
+
+/// A deck's simulation inputs: its own new/review daily limits (nested-limit
+/// semantics - a parent's limit caps the sum of its children - are resolved
+/// separately during simulation by walking the `parents` map passed to
+/// `simulate_study_load`, since that needs the ancestry of every deck in the
+/// run, not just this one) and the cards currently due for it, bucketed by
+/// how many (simulated) days from now they fall due. `filtered` decks are
+/// excluded by the caller before simulation, per the usual rule that they
+/// don't participate in the regular scheduler.
+#[derive(Debug, Clone)]
+pub(crate) struct DeckSimInput {
+    pub(crate) did: DeckID,
+    pub(crate) new_limit: u32,
+    pub(crate) review_limit: u32,
+    /// new cards not yet introduced, available to draw from on any day
+    pub(crate) new_cards_remaining: u32,
+    /// reviews due, keyed by day offset from the start of the simulation
+    /// (0 = due today); cards that fail are pushed back into this map at a
+    /// later offset within the same trial
+    pub(crate) due_by_day: HashMap<u32, u32>,
+}
+
+/// Mean and selected percentiles of the realized count for a single
+/// (deck, day) cell across all trials.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct SimStats {
+    pub(crate) mean: f32,
+    pub(crate) p50: u32,
+    pub(crate) p90: u32,
+}
+
+/// Mirrors the shape `CountsForDeckTodayOut` uses for a single day, but
+/// indexed by (deck, future day) instead of just the current day; this is
+/// what a `SimulateStudyLoadOut` proto message would carry field-for-field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct DeckDaySimResult {
+    pub(crate) did: DeckID,
+    pub(crate) day: u32,
+    pub(crate) new: SimStats,
+    pub(crate) review: SimStats,
+}
+
+/// Runs `trials` independent simulations of the next `days` days and
+/// accumulates per-(deck, day) new/review counts. Reproducible: the same
+/// `rng` stream (i.e. the same seed, if the caller constructs a seeded RNG)
+/// always produces the same result.
+pub(crate) fn simulate_study_load(
+    decks: &[DeckSimInput],
+    parents: &HashMap<DeckID, Vec<DeckID>>,
+    days: u32,
+    trials: u32,
+    pass_probability: f32,
+    rng: &mut impl Rng,
+) -> Vec<DeckDaySimResult> {
+    // raw per-trial samples, keyed by (deck, day); collected across all
+    // trials so percentiles can be taken at the end
+    let mut new_samples: HashMap<(DeckID, u32), Vec<u32>> = HashMap::new();
+    let mut review_samples: HashMap<(DeckID, u32), Vec<u32>> = HashMap::new();
+
+    // top-down: every deck is visited after all of its ancestors
+    let mut top_down = decks.to_vec();
+    top_down.sort_by_key(|d| parents.get(&d.did).map_or(0, Vec::len));
+
+    for _ in 0..trials {
+        let mut trial = top_down.clone();
+        for day in 0..days {
+            let (new_today, review_today) = simulate_one_day(&mut trial, parents, day, pass_probability, rng);
+            for (did, count) in new_today {
+                new_samples.entry((did, day)).or_default().push(count);
+            }
+            for (did, count) in review_today {
+                review_samples.entry((did, day)).or_default().push(count);
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    for did_day in new_samples.keys().copied().collect::<Vec<_>>() {
+        let new = percentiles(&new_samples[&did_day]);
+        let review = review_samples
+            .get(&did_day)
+            .map(|v| percentiles(v))
+            .unwrap_or_default();
+        out.push(DeckDaySimResult {
+            did: did_day.0,
+            day: did_day.1,
+            new,
+            review,
+        });
+    }
+    out.sort_unstable_by_key(|r| (r.did.0, r.day));
+    out
+}
+
+/// Simulates a single day for a single trial, mutating `decks` in place
+/// (consuming new-card supply, rescheduling failed reviews into later
+/// days - i.e. relearning - within this trial) and returns the realized
+/// new/review counts for that day, keyed by deck.
+fn simulate_one_day(
+    decks: &mut [DeckSimInput],
+    parents: &HashMap<DeckID, Vec<DeckID>>,
+    day: u32,
+    pass_probability: f32,
+    rng: &mut impl Rng,
+) -> (Vec<(DeckID, u32)>, Vec<(DeckID, u32)>) {
+    // a parent's limit caps the sum of what its children draw, so every
+    // deck's own limit is resolved against it in `limits_by_id` (decks
+    // don't gain or lose limits within a day, just cards) while
+    // `new_consumed`/`review_consumed` accumulate, as the day is walked,
+    // how much each deck (including its role as an ancestor of others) has
+    // drawn so far
+    let limits_by_id: HashMap<DeckID, (u32, u32)> = decks
+        .iter()
+        .map(|d| (d.did, (d.new_limit, d.review_limit)))
+        .collect();
+    let mut new_consumed: HashMap<DeckID, u32> = HashMap::new();
+    let mut review_consumed: HashMap<DeckID, u32> = HashMap::new();
+
+    let mut new_counts = Vec::with_capacity(decks.len());
+    let mut review_counts = Vec::with_capacity(decks.len());
+    let mut relearn_today = HashMap::new();
+
+    for deck in decks.iter_mut() {
+        let (review_ancestor_cap, new_ancestor_cap) = ancestor_remaining_caps(
+            deck.did,
+            parents,
+            &limits_by_id,
+            &new_consumed,
+            &review_consumed,
+        );
+
+        let due_today = deck.due_by_day.remove(&day).unwrap_or(0);
+        let review_cap = deck.review_limit.min(review_ancestor_cap);
+        let reviews_done = due_today.min(review_cap);
+
+        let mut failed = 0;
+        for _ in 0..reviews_done {
+            if rng.gen::<f32>() >= pass_probability {
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            *relearn_today.entry(deck.did).or_insert(0) += failed;
+        }
+        // anything beyond today's cap stays due, pushed to tomorrow
+        if due_today > reviews_done {
+            *deck.due_by_day.entry(day + 1).or_insert(0) += due_today - reviews_done;
+        }
+
+        let new_cap = deck.new_limit.min(new_ancestor_cap);
+        let new_done = deck.new_cards_remaining.min(new_cap);
+        deck.new_cards_remaining -= new_done;
+
+        // this deck's draws count against its own remaining cap should it
+        // also be an ancestor of another deck, and against every one of its
+        // own ancestors' caps
+        let ancestors = parents.get(&deck.did).into_iter().flatten().copied();
+        for id in std::iter::once(deck.did).chain(ancestors) {
+            *new_consumed.entry(id).or_insert(0) += new_done;
+            *review_consumed.entry(id).or_insert(0) += reviews_done;
+        }
+
+        new_counts.push((deck.did, new_done));
+        review_counts.push((deck.did, reviews_done));
+    }
+
+    // failed reviews become due again the next (simulated) day, modelling
+    // relearning without needing the full scheduler's interval curve
+    for (did, count) in relearn_today {
+        if let Some(deck) = decks.iter_mut().find(|d| d.did == did) {
+            *deck.due_by_day.entry(day + 1).or_insert(0) += count;
+        }
+    }
+
+    (new_counts, review_counts)
+}
+
+/// Walks `did`'s ancestors (as given by `parents`, which maps a deck to its
+/// chain of ancestor ids) and returns the tightest `(review, new)` cap any
+/// of them still has room for today - a parent's limit caps the sum of
+/// everything drawn across its whole subtree, not just its direct
+/// children, so this is what keeps nested limits honest.
+fn ancestor_remaining_caps(
+    did: DeckID,
+    parents: &HashMap<DeckID, Vec<DeckID>>,
+    limits_by_id: &HashMap<DeckID, (u32, u32)>,
+    new_consumed: &HashMap<DeckID, u32>,
+    review_consumed: &HashMap<DeckID, u32>,
+) -> (u32, u32) {
+    let mut review_cap = u32::MAX;
+    let mut new_cap = u32::MAX;
+    for ancestor in parents.get(&did).into_iter().flatten() {
+        if let Some((ancestor_new_limit, ancestor_review_limit)) = limits_by_id.get(ancestor) {
+            let new_used = *new_consumed.get(ancestor).unwrap_or(&0);
+            let review_used = *review_consumed.get(ancestor).unwrap_or(&0);
+            let new_remaining = ancestor_new_limit.saturating_sub(new_used);
+            let review_remaining = ancestor_review_limit.saturating_sub(review_used);
+            new_cap = new_cap.min(new_remaining);
+            review_cap = review_cap.min(review_remaining);
+        }
+    }
+    (review_cap, new_cap)
+}
+
+fn percentiles(samples: &[u32]) -> SimStats {
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    let mean = sorted.iter().sum::<u32>() as f32 / sorted.len() as f32;
+    SimStats {
+        mean,
+        p50: percentile(&sorted, 0.5),
+        p90: percentile(&sorted, 0.9),
+    }
+}
+
+fn percentile(sorted: &[u32], p: f32) -> u32 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let idx = ((sorted.len() - 1) as f32 * p).round() as usize;
+    sorted[idx.min(sorted.len() - 1)]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn input(did: i64, new_limit: u32, review_limit: u32, due_today: u32) -> DeckSimInput {
+        let mut due_by_day = HashMap::new();
+        due_by_day.insert(0, due_today);
+        DeckSimInput {
+            did: DeckID(did),
+            new_limit,
+            review_limit,
+            new_cards_remaining: 20,
+            due_by_day,
+        }
+    }
+
+    #[test]
+    fn reproducible_given_fixed_seed() {
+        let decks = vec![input(1, 10, 10, 5)];
+        let parents = HashMap::new();
+
+        let mut rng1 = StdRng::seed_from_u64(42);
+        let out1 = simulate_study_load(&decks, &parents, 3, 20, 0.85, &mut rng1);
+
+        let mut rng2 = StdRng::seed_from_u64(42);
+        let out2 = simulate_study_load(&decks, &parents, 3, 20, 0.85, &mut rng2);
+
+        assert_eq!(out1, out2);
+    }
+
+    #[test]
+    fn respects_review_limit() {
+        let decks = vec![input(1, 0, 3, 10)];
+        let parents = HashMap::new();
+        let mut rng = StdRng::seed_from_u64(1);
+        let out = simulate_study_load(&decks, &parents, 1, 5, 1.0, &mut rng);
+        let day0 = out.iter().find(|r| r.day == 0).unwrap();
+        assert!(day0.review.mean <= 3.0);
+    }
+
+    #[test]
+    fn parent_limit_caps_sum_of_children() {
+        // two children each individually allowed 10 reviews, but their
+        // shared parent only has room for 5 across the whole subtree
+        let decks = vec![
+            input(2, 0, 10, 10),
+            input(3, 0, 10, 10),
+            input(1, 0, 5, 0),
+        ];
+        let mut parents = HashMap::new();
+        parents.insert(DeckID(2), vec![DeckID(1)]);
+        parents.insert(DeckID(3), vec![DeckID(1)]);
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let out = simulate_study_load(&decks, &parents, 1, 1, 1.0, &mut rng);
+        let total: u32 = out
+            .iter()
+            .filter(|r| r.day == 0 && r.did != DeckID(1))
+            .map(|r| r.review.mean as u32)
+            .sum();
+        assert!(total <= 5);
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use super::{Note, NoteID};
+use crate::{notetype::NoteTypeID, prelude::*};
+use std::collections::HashSet;
+
+/// The nature of the relationship a `NoteLink` records between two notes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NoteLinkKind {
+    /// A loose "see also"-style reference. This is also the kind used for
+    /// links `sync_derived_links` derives automatically from field content.
+    Reference,
+    /// `source` is the parent of `target` in a hierarchy the user has laid
+    /// out over their notes, independent of the deck tree.
+    Parent,
+    /// The inverse of `Parent` - `source` is the child of `target`.
+    Child,
+}
+
+/// A directed link from `source` to `target`. Backreferences aren't stored
+/// separately - `backreferences_to` is just a lookup by `target` instead of
+/// `source`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteLink {
+    pub source: NoteID,
+    pub target: NoteID,
+    pub kind: NoteLinkKind,
+}
+
+#[derive(Debug)]
+pub(crate) enum UndoableNoteLinkChange {
+    Added(Box<NoteLink>),
+    Removed(Box<NoteLink>),
+}
+
+/// Marks the start of an in-field note reference, e.g. `[[note:1234]]`.
+/// `sync_derived_links` looks for these in a notetype's configured link
+/// field and keeps that note's outgoing `Reference` links in sync with
+/// whatever ids are present.
+const LINK_MARKER_PREFIX: &str = "[[note:";
+const LINK_MARKER_SUFFIX: &str = "]]";
+
+impl Collection {
+    pub(crate) fn undo_note_link_change(&mut self, change: UndoableNoteLinkChange) -> Result<()> {
+        match change {
+            UndoableNoteLinkChange::Added(link) => self.remove_note_link_undoable(*link),
+            UndoableNoteLinkChange::Removed(link) => self.add_note_link_undoable(*link),
+        }
+    }
+
+    /// Record a link from `source` to `target`, saving an undo entry.
+    pub fn add_note_link(
+        &mut self,
+        source: NoteID,
+        target: NoteID,
+        kind: NoteLinkKind,
+    ) -> Result<OpOutput<()>> {
+        self.transact(Op::UpdateNote, |col| {
+            col.add_note_link_undoable(NoteLink {
+                source,
+                target,
+                kind,
+            })
+        })
+    }
+
+    /// Remove a previously recorded link, saving an undo entry.
+    pub fn remove_note_link(
+        &mut self,
+        source: NoteID,
+        target: NoteID,
+        kind: NoteLinkKind,
+    ) -> Result<OpOutput<()>> {
+        self.transact(Op::UpdateNote, |col| {
+            col.remove_note_link_undoable(NoteLink {
+                source,
+                target,
+                kind,
+            })
+        })
+    }
+
+    /// All links with `nid` as their source.
+    pub fn links_from(&self, nid: NoteID) -> Result<Vec<NoteLink>> {
+        self.storage.note_links_from(nid)
+    }
+
+    /// All links that point at `nid` - the notes that reference it.
+    pub fn backreferences_to(&self, nid: NoteID) -> Result<Vec<NoteLink>> {
+        self.storage.note_links_to(nid)
+    }
+
+    pub(crate) fn add_note_link_undoable(&mut self, link: NoteLink) -> Result<()> {
+        self.storage.add_note_link(&link)?;
+        self.save_undo(UndoableNoteLinkChange::Added(Box::new(link)));
+        Ok(())
+    }
+
+    pub(crate) fn remove_note_link_undoable(&mut self, link: NoteLink) -> Result<()> {
+        self.storage
+            .remove_note_link(link.source, link.target, link.kind)?;
+        self.save_undo(UndoableNoteLinkChange::Removed(Box::new(link)));
+        Ok(())
+    }
+
+    /// Removes every link touching `nid` (as either source or target),
+    /// recording an undo entry for each. Called when the note itself is
+    /// being removed, so the graph doesn't end up with dangling edges
+    /// pointing at a note that no longer exists.
+    pub(crate) fn remove_note_links_touching_undoable(&mut self, nid: NoteID) -> Result<()> {
+        for link in self.storage.remove_note_links_touching(nid)? {
+            self.save_undo(UndoableNoteLinkChange::Removed(Box::new(link)));
+        }
+        Ok(())
+    }
+
+    /// The field index `sync_derived_links` should scan for this notetype,
+    /// if one has been configured.
+    pub fn link_field_for_notetype(&self, ntid: NoteTypeID) -> Option<u32> {
+        self.get_config_optional(link_field_config_key(ntid).as_str())
+    }
+
+    /// Configures (or clears, with `None`) the field `sync_derived_links`
+    /// scans for in-field note references for this notetype. Like other
+    /// config changes, this isn't part of the undo history.
+    pub fn set_link_field_for_notetype(
+        &mut self,
+        ntid: NoteTypeID,
+        field_idx: Option<u32>,
+    ) -> Result<()> {
+        let key = link_field_config_key(ntid);
+        self.transact_no_undo(|col| match field_idx {
+            Some(idx) => col.set_config(key.as_str(), &idx),
+            None => col.remove_config(key.as_str()),
+        })
+    }
+
+    /// If this notetype has a configured link field, re-scans it and
+    /// updates `note`'s outgoing `Reference` links to match exactly the set
+    /// of note ids mentioned in the field, other than `note`'s own id -
+    /// adding newly-mentioned ones and removing ones that have since been
+    /// edited out. A no-op for notetypes
+    /// with no configured link field.
+    pub(crate) fn sync_derived_links(&mut self, note: &Note) -> Result<()> {
+        let field_idx = match self.link_field_for_notetype(note.notetype_id) {
+            Some(idx) => idx as usize,
+            None => return Ok(()),
+        };
+        let field = match note.fields().get(field_idx) {
+            Some(field) => field,
+            None => return Ok(()),
+        };
+
+        let wanted: HashSet<NoteID> = note_ids_in_field(field).into_iter().collect();
+        let existing: Vec<NoteLink> = self
+            .links_from(note.id)?
+            .into_iter()
+            .filter(|link| link.kind == NoteLinkKind::Reference)
+            .collect();
+
+        for link in &existing {
+            if !wanted.contains(&link.target) {
+                self.remove_note_link_undoable(link.clone())?;
+            }
+        }
+        let already_linked: HashSet<NoteID> = existing.iter().map(|link| link.target).collect();
+        for target in wanted {
+            if target != note.id && !already_linked.contains(&target) {
+                self.add_note_link_undoable(NoteLink {
+                    source: note.id,
+                    target,
+                    kind: NoteLinkKind::Reference,
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn link_field_config_key(ntid: NoteTypeID) -> String {
+    format!("linkField_{}", ntid.0)
+}
+
+/// Extracts the note ids referenced by `[[note:1234]]`-style markers in
+/// `field`.
+fn note_ids_in_field(field: &str) -> Vec<NoteID> {
+    let mut ids = Vec::new();
+    let mut rest = field;
+    while let Some(start) = rest.find(LINK_MARKER_PREFIX) {
+        rest = &rest[start + LINK_MARKER_PREFIX.len()..];
+        if let Some(end) = rest.find(LINK_MARKER_SUFFIX) {
+            if let Ok(nid) = rest[..end].parse::<i64>() {
+                ids.push(NoteID(nid));
+            }
+            rest = &rest[end + LINK_MARKER_SUFFIX.len()..];
+        } else {
+            break;
+        }
+    }
+    ids
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_note_ids_from_field() {
+        let field = "See also [[note:42]] and [[note:7]], plus some text.";
+        assert_eq!(note_ids_in_field(field), vec![NoteID(42), NoteID(7)]);
+        assert_eq!(note_ids_in_field("no links here"), Vec::<NoteID>::new());
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Finds groups of notes that are exact duplicates of one another (same
+//! notetype, same stripped first field) within a caller-supplied selection,
+//! and collapses each group down to a single surviving note - the one with
+//! the lowest id. The other notes' cards are reassigned to the survivor
+//! (dropping any whose ordinal collides with a card the survivor already
+//! has), their tags are unioned onto it, and the now-redundant notes are
+//! removed.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{field_checksum, Note, NoteID};
+use crate::{prelude::*, text::strip_html_preserving_media_filenames};
+
+pub struct MergeDuplicateNotesOut {
+    pub merged_notes: usize,
+    pub reassigned_cards: usize,
+}
+
+/// Groups `notes` by `(notetype, exact stripped first field)`, discarding
+/// groups of size one. Notes with an empty first field are never
+/// considered duplicates of one another.
+///
+/// Candidates sharing a field checksum are still verified against each
+/// other by direct string comparison, the same way
+/// `note_is_duplicate_or_empty` double-checks a checksum match, so a
+/// checksum collision can't merge two genuinely different notes.
+fn duplicate_clusters(notes: &[Note]) -> Vec<Vec<NoteID>> {
+    let mut by_checksum: HashMap<(i64, u32), Vec<(String, NoteID)>> = HashMap::new();
+    for note in notes {
+        let field1 = match note.fields.get(0) {
+            Some(field1) => field1,
+            None => continue,
+        };
+        let stripped = strip_html_preserving_media_filenames(field1);
+        if stripped.trim().is_empty() {
+            continue;
+        }
+        let csum = field_checksum(&stripped);
+        by_checksum
+            .entry((note.notetype_id.0, csum))
+            .or_default()
+            .push((stripped.into_owned(), note.id));
+    }
+
+    let mut clusters = Vec::new();
+    for candidates in by_checksum.into_values() {
+        let mut groups: Vec<(String, Vec<NoteID>)> = Vec::new();
+        for (stripped, nid) in candidates {
+            match groups.iter_mut().find(|(text, _)| *text == stripped) {
+                Some((_, ids)) => ids.push(nid),
+                None => groups.push((stripped, vec![nid])),
+            }
+        }
+        clusters.extend(groups.into_iter().map(|(_, ids)| ids).filter(|ids| ids.len() > 1));
+    }
+    clusters
+}
+
+impl Collection {
+    /// Among `nids`, find notes that are exact duplicates of one another
+    /// and merge each group down to its lowest-id member.
+    pub fn merge_duplicate_notes(
+        &mut self,
+        nids: &[NoteID],
+    ) -> Result<OpOutput<MergeDuplicateNotesOut>> {
+        let usn = self.usn()?;
+        self.transact(Op::RemoveNote, |col| {
+            col.merge_duplicate_notes_inner(nids, usn)
+        })
+    }
+
+    fn merge_duplicate_notes_inner(
+        &mut self,
+        nids: &[NoteID],
+        usn: Usn,
+    ) -> Result<MergeDuplicateNotesOut> {
+        let mut notes = Vec::with_capacity(nids.len());
+        for nid in nids {
+            if let Some(note) = self.storage.get_note(*nid)? {
+                notes.push(note);
+            }
+        }
+
+        let mut out = MergeDuplicateNotesOut {
+            merged_notes: 0,
+            reassigned_cards: 0,
+        };
+
+        for mut cluster in duplicate_clusters(&notes) {
+            cluster.sort();
+            let survivor_id = cluster[0];
+            let mut survivor = self
+                .storage
+                .get_note(survivor_id)?
+                .ok_or(AnkiError::NotFound)?;
+            let original_survivor = survivor.clone();
+            let nt = self
+                .get_notetype(survivor.notetype_id)?
+                .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
+
+            let mut taken_ords: HashSet<_> = self
+                .storage
+                .all_cards_of_note(survivor_id)?
+                .into_iter()
+                .map(|card| card.ord)
+                .collect();
+
+            for &dupe_id in &cluster[1..] {
+                let dupe = self.storage.get_note(dupe_id)?.ok_or(AnkiError::NotFound)?;
+                for mut card in self.storage.all_cards_of_note(dupe_id)? {
+                    if taken_ords.insert(card.ord) {
+                        let original_card = card.clone();
+                        card.note_id = survivor_id;
+                        self.update_card_inner(&mut card, original_card, usn)?;
+                        out.reassigned_cards += 1;
+                    } else {
+                        self.remove_card_and_add_grave_undoable(card, usn)?;
+                    }
+                }
+                survivor.tags.extend(dupe.tags);
+                self.remove_note_links_touching_undoable(dupe_id)?;
+                self.remove_note_only_undoable(dupe_id, usn)?;
+                out.merged_notes += 1;
+            }
+
+            if cluster.len() > 1 {
+                survivor.tags.sort();
+                survivor.tags.dedup();
+                self.canonify_note_tags(&mut survivor, usn)?;
+                self.update_note_inner_without_cards(
+                    &mut survivor,
+                    &original_survivor,
+                    &nt,
+                    usn,
+                    true,
+                    false,
+                )?;
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn note(id: i64, notetype_id: i64, field1: &str) -> Note {
+        Note::new_from_storage(
+            NoteID(id),
+            String::new(),
+            crate::notetype::NoteTypeID(notetype_id),
+            TimestampSecs(0),
+            Usn(0),
+            vec![],
+            vec![field1.to_string()],
+            None,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn clusters_exact_duplicates_within_a_notetype() {
+        let notes = vec![
+            note(1, 1, "hello"),
+            note(2, 1, "hello"),
+            note(3, 1, "goodbye"),
+            note(4, 2, "hello"),
+        ];
+        let mut clusters = duplicate_clusters(&notes);
+        for cluster in &mut clusters {
+            cluster.sort();
+        }
+        assert_eq!(clusters, vec![vec![NoteID(1), NoteID(2)]]);
+    }
+
+    #[test]
+    fn empty_first_fields_are_never_clustered() {
+        let notes = vec![note(1, 1, ""), note(2, 1, "")];
+        assert!(duplicate_clusters(&notes).is_empty());
+    }
+}
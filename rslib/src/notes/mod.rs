@@ -1,6 +1,8 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+pub(crate) mod links;
+pub(crate) mod merge;
 pub(crate) mod undo;
 
 use crate::{
@@ -22,8 +24,9 @@ use itertools::Itertools;
 use num_integer::Integer;
 use std::{
     borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     convert::TryInto,
+    hash::{Hash, Hasher},
 };
 
 define_newtype!(NoteID, i64);
@@ -46,6 +49,11 @@ pub struct Note {
     fields: Vec<String>,
     pub(crate) sort_field: Option<String>,
     pub(crate) checksum: Option<u32>,
+    /// MinHash signature of the first field, used to estimate Jaccard
+    /// similarity for near-duplicate detection. Kept in sync with
+    /// `checksum` - both are recomputed in `prepare_for_update` and cleared
+    /// by `mark_dirty`.
+    pub(crate) minhash: Option<MinHashSignature>,
 }
 
 /// Information required for updating tags while leaving note content alone.
@@ -77,6 +85,7 @@ impl Note {
             fields: vec!["".to_string(); notetype.fields.len()],
             sort_field: None,
             checksum: None,
+            minhash: None,
         }
     }
 
@@ -91,6 +100,7 @@ impl Note {
         fields: Vec<String>,
         sort_field: Option<String>,
         checksum: Option<u32>,
+        minhash: Option<MinHashSignature>,
     ) -> Self {
         Self {
             id,
@@ -102,6 +112,7 @@ impl Note {
             fields,
             sort_field,
             checksum,
+            minhash,
         }
     }
 
@@ -118,6 +129,7 @@ impl Note {
     fn mark_dirty(&mut self) {
         self.sort_field = None;
         self.checksum = None;
+        self.minhash = None;
     }
 
     pub fn set_field(&mut self, idx: usize, text: impl Into<String>) -> Result<()> {
@@ -159,6 +171,7 @@ impl Note {
 
         let field1_nohtml = strip_html_preserving_media_filenames(&self.fields()[0]);
         let checksum = field_checksum(field1_nohtml.as_ref());
+        let minhash = minhash_signature(field1_nohtml.as_ref());
         let sort_field = if nt.config.sort_field_idx == 0 {
             field1_nohtml
         } else {
@@ -171,6 +184,7 @@ impl Note {
         };
         self.sort_field = Some(sort_field.into());
         self.checksum = Some(checksum);
+        self.minhash = Some(minhash);
         Ok(())
     }
 
@@ -209,6 +223,33 @@ impl Note {
             .collect()
     }
 
+    /// A stable fingerprint of this note's full content: every field (NFC
+    /// normalized and HTML-stripped), the sorted canonical tags, and the
+    /// notetype id. Unlike `checksum`, which only covers the truncated first
+    /// field and exists purely for candidate lookup, this is order
+    /// independent in the tags and covers everything, so two notes that
+    /// fingerprint equally have the same notetype, tags, and field text -
+    /// the property import-time deduplication needs to make re-importing the
+    /// same deck idempotent. Differences only in HTML markup or Unicode
+    /// normalization form do not change the fingerprint.
+    pub fn content_fingerprint(&self, nt: &NoteType) -> ContentFingerprint {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&nt.id.0.to_be_bytes());
+        for field in &self.fields {
+            let normalized = normalize_to_nfc(field);
+            let stripped = strip_html_preserving_media_filenames(&normalized);
+            buf.push(0x1f);
+            buf.extend_from_slice(stripped.as_bytes());
+        }
+        let mut tags = self.tags.clone();
+        tags.sort();
+        for tag in &tags {
+            buf.push(0x1f);
+            buf.extend_from_slice(tag.as_bytes());
+        }
+        sha1::Sha1::from(&buf).digest().bytes()
+    }
+
     /// Pad or merge fields to match note type.
     pub(crate) fn fix_field_count(&mut self, nt: &NoteType) {
         while self.fields.len() < nt.fields.len() {
@@ -250,6 +291,7 @@ impl From<pb::Note> for Note {
             fields: n.fields,
             sort_field: None,
             checksum: None,
+            minhash: None,
         }
     }
 }
@@ -261,6 +303,86 @@ pub(crate) fn field_checksum(text: &str) -> u32 {
     u32::from_be_bytes(digest[..4].try_into().unwrap())
 }
 
+/// A full SHA1 digest over a note's entire content, as produced by
+/// `Note::content_fingerprint`.
+pub(crate) type ContentFingerprint = [u8; 20];
+
+/// A MinHash sketch of a field, used to estimate Jaccard similarity between
+/// two notes' first fields without comparing every shingle directly.
+pub(crate) type MinHashSignature = Vec<u64>;
+
+/// Length of a `MinHashSignature`, and the number of distinct hash seeds
+/// used to build one.
+const MINHASH_SIGNATURE_LEN: usize = 64;
+
+/// Width (in characters) of the overlapping shingles MinHash is built from.
+const SHINGLE_SIZE: usize = 3;
+
+/// Number of LSH bands the signature is split into (`MINHASH_SIGNATURE_LEN`
+/// must be evenly divisible by this). Two notes are candidates for
+/// near-duplicate comparison if any one of their bands hashes to the same
+/// bucket.
+const LSH_BANDS: usize = 16;
+const LSH_ROWS_PER_BAND: usize = MINHASH_SIGNATURE_LEN / LSH_BANDS;
+
+/// Jaccard similarity (estimated from the fraction of equal MinHash slots)
+/// above which `near_duplicate_note_ids` reports a note as a near-duplicate.
+pub(crate) const NEAR_DUPLICATE_THRESHOLD: f32 = 0.8;
+
+/// Splits `text` into overlapping `SHINGLE_SIZE`-character windows. Text
+/// shorter than a full shingle is treated as a single shingle, so short
+/// fields still get a (degenerate) signature instead of an empty one.
+fn shingles(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= SHINGLE_SIZE {
+        return vec![chars.into_iter().collect()];
+    }
+    chars
+        .windows(SHINGLE_SIZE)
+        .map(|window| window.iter().collect())
+        .collect()
+}
+
+fn seeded_hash(seed: u64, value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Builds a MinHash signature for `text`: for each of `MINHASH_SIGNATURE_LEN`
+/// hash seeds, the minimum hash over all of the text's shingles.
+pub(crate) fn minhash_signature(text: &str) -> MinHashSignature {
+    let shingles = shingles(text);
+    (0..MINHASH_SIGNATURE_LEN as u64)
+        .map(|seed| {
+            shingles
+                .iter()
+                .map(|shingle| seeded_hash(seed, shingle))
+                .min()
+                .unwrap()
+        })
+        .collect()
+}
+
+/// Estimates the Jaccard similarity of the two fields a pair of MinHash
+/// signatures were built from, as the fraction of signature slots that
+/// agree.
+fn estimated_similarity(a: &[u64], b: &[u64]) -> f32 {
+    let equal = a.iter().zip(b.iter()).filter(|(x, y)| x == y).count();
+    equal as f32 / MINHASH_SIGNATURE_LEN as f32
+}
+
+/// Splits a MinHash signature into `LSH_BANDS` bucket keys, one per band.
+/// Two signatures sharing any one `(band, bucket)` key are LSH candidates
+/// for a direct similarity comparison.
+fn lsh_buckets(signature: &[u64]) -> impl Iterator<Item = (u8, u64)> + '_ {
+    signature
+        .chunks(LSH_ROWS_PER_BAND)
+        .enumerate()
+        .map(|(band, rows)| (band as u8, seeded_hash(band as u64, rows)))
+}
+
 pub(crate) fn guid() -> String {
     anki_base91(rand::random())
 }
@@ -311,6 +433,15 @@ impl Collection {
     ) -> Result<()> {
         self.canonify_note_tags(note, ctx.usn)?;
         note.prepare_for_update(&ctx.notetype, normalize_text)?;
+
+        let fingerprint = note.content_fingerprint(&ctx.notetype);
+        if let Some(existing_id) = self.storage.note_id_by_content_fingerprint(fingerprint)? {
+            // Byte-identical to a note we already have - reuse it instead of
+            // creating a duplicate, so reimporting the same deck is a no-op.
+            note.id = existing_id;
+            return Ok(());
+        }
+
         note.set_modified(ctx.usn);
         self.add_note_only_undoable(note)?;
         self.generate_cards_for_new_note(ctx, note, did)?;
@@ -398,7 +529,8 @@ impl Collection {
         if mark_note_modified {
             note.set_modified(usn);
         }
-        self.update_note_undoable(note, original)
+        self.update_note_undoable(note, original)?;
+        self.sync_derived_links(note)
     }
 
     /// Remove provided notes, and any cards that use them.
@@ -411,6 +543,7 @@ impl Collection {
                     for card in col.storage.all_cards_of_note(nid)? {
                         col.remove_card_and_add_grave_undoable(card, usn)?;
                     }
+                    col.remove_note_links_touching_undoable(nid)?;
                     col.remove_note_only_undoable(nid, usn)?;
                 }
             }
@@ -524,6 +657,60 @@ impl Collection {
         }
     }
 
+    /// Note ids (other than `note`'s own) whose first field is within
+    /// `threshold` estimated Jaccard similarity of `note`'s - catches near
+    /// duplicates that differ by a word, some punctuation, or word order,
+    /// which `note_is_duplicate_or_empty`'s exact checksum match would miss.
+    /// Exact duplicates have an estimated similarity of 1.0, so they're
+    /// always included without a separate checksum lookup; a similarity of
+    /// 1.0 does not by itself mean the two fields are identical.
+    ///
+    /// Candidates are found via LSH banding rather than a full scan: the
+    /// signature is split into `LSH_BANDS` bands, and only notes sharing a
+    /// `(notetype_id, band, band_hash)` bucket with `note` are compared
+    /// directly.
+    pub(crate) fn near_duplicate_note_ids(
+        &self,
+        note: &Note,
+        threshold: f32,
+    ) -> Result<HashSet<NoteID>> {
+        let field1 = match note.fields.get(0) {
+            Some(field1) => field1,
+            None => return Ok(HashSet::new()),
+        };
+        let field1 = if self.get_bool(BoolKey::NormalizeNoteText) {
+            normalize_to_nfc(field1)
+        } else {
+            field1.into()
+        };
+        let stripped = strip_html_preserving_media_filenames(&field1);
+        if stripped.trim().is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let signature = minhash_signature(&stripped);
+        let mut candidates = HashSet::new();
+        for (band, bucket) in lsh_buckets(&signature) {
+            candidates.extend(
+                self.storage
+                    .note_ids_in_lsh_bucket(note.notetype_id, band, bucket)?,
+            );
+        }
+        candidates.remove(&note.id);
+
+        let mut near_dupes = HashSet::new();
+        for nid in candidates {
+            if let Some(candidate) = self.storage.get_note(nid)? {
+                if let Some(candidate_signature) = &candidate.minhash {
+                    if estimated_similarity(&signature, candidate_signature) >= threshold {
+                        near_dupes.insert(nid);
+                    }
+                }
+            }
+        }
+        Ok(near_dupes)
+    }
+
     /// Update the tags of the provided note, canonifying before save. Requires a transaction.
     /// Fixme: this currently pulls in the note type, and does more work than necessary. We
     /// could add a separate method to the storage layer to just update the tags in the future,
@@ -559,21 +746,46 @@ impl Collection {
 fn note_differs_from_db(existing_note: &mut Note, note: &mut Note) -> bool {
     let sort_field = existing_note.sort_field.take();
     let checksum = existing_note.checksum.take();
+    let minhash = existing_note.minhash.take();
     note.mtime = existing_note.mtime;
     let notes_differ = existing_note != note;
     existing_note.sort_field = sort_field;
     existing_note.checksum = checksum;
+    existing_note.minhash = minhash;
     notes_differ
 }
 
 #[cfg(test)]
 mod test {
-    use super::{anki_base91, field_checksum};
+    use super::{anki_base91, estimated_similarity, field_checksum, minhash_signature};
     use crate::{
         collection::open_test_collection, config::BoolKey, decks::DeckID, err::Result, prelude::*,
         search::SortMode,
     };
 
+    #[test]
+    fn content_fingerprint_ignores_tag_order_but_not_field_content() -> Result<()> {
+        let mut col = open_test_collection();
+        let nt = col
+            .get_notetype_by_name("basic (and reversed card)")?
+            .unwrap();
+
+        let mut note = nt.new_note();
+        note.fields[0] = "front".into();
+        note.tags = vec!["b".into(), "a".into()];
+        let fp = note.content_fingerprint(&nt);
+
+        let mut reordered = note.clone();
+        reordered.tags = vec!["a".into(), "b".into()];
+        assert_eq!(fp, reordered.content_fingerprint(&nt));
+
+        let mut edited = note.clone();
+        edited.fields[0] = "different front".into();
+        assert_ne!(fp, edited.content_fingerprint(&nt));
+
+        Ok(())
+    }
+
     #[test]
     fn test_base91() {
         // match the python implementation for now
@@ -589,6 +801,26 @@ mod test {
         assert_eq!(field_checksum("今日"), 1464653051);
     }
 
+    #[test]
+    fn minhash_similarity_decreases_with_edits() {
+        let original = "the quick brown fox jumps over the lazy dog";
+        let identical = minhash_signature(original);
+        assert_eq!(estimated_similarity(&minhash_signature(original), &identical), 1.0);
+
+        let one_word_changed = "the quick brown fox leaps over the lazy dog";
+        let unrelated = "completely different content about something else entirely";
+
+        let close_similarity = estimated_similarity(
+            &minhash_signature(original),
+            &minhash_signature(one_word_changed),
+        );
+        let far_similarity =
+            estimated_similarity(&minhash_signature(original), &minhash_signature(unrelated));
+
+        assert!(close_similarity > far_similarity);
+        assert!(close_similarity > 0.5);
+    }
+
     #[test]
     fn adding_cards() -> Result<()> {
         let mut col = open_test_collection();
@@ -11,8 +11,8 @@ use crate::{
     search::SortMode,
     types::Usn,
 };
-use rand::seq::SliceRandom;
-use std::collections::{HashMap, HashSet};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 impl Card {
     fn schedule_as_new(&mut self, position: u32) {
@@ -38,29 +38,103 @@ pub(crate) struct NewCardSorter {
     position: HashMap<NoteID, u32>,
 }
 
+/// Outcome of `sort_cards`/`sort_deck`: how many cards moved, and - for a
+/// `Random`/`Spread` order - the seed that was used, so a caller can log it
+/// and replay the same shuffle later.
+pub struct SortCardsOut {
+    pub changed: usize,
+    pub seed: Option<u64>,
+}
+
 #[derive(PartialEq)]
 pub enum NewCardSortOrder {
     NoteId,
-    Random,
+    /// Shuffles new cards into a random order. Carrying a seed makes the
+    /// shuffle reproducible - `NewCardSorter::new_with_seed` draws a fresh
+    /// one when `None`, so a randomized "spread new cards" operation can be
+    /// replayed exactly on another device, or in a test.
+    Random { seed: Option<u64> },
+    /// Like `Random`, but additionally guarantees no two notes in the same
+    /// group (see `NewCardSorter::new_with_seed`'s `groups` map) land
+    /// within `min_gap` positions of each other - useful so cards from the
+    /// same notetype or sharing a tag don't get introduced back-to-back.
+    Spread { min_gap: u32, seed: Option<u64> },
     Preserve,
 }
 
+/// How many full random permutations `Spread` tries before giving up and
+/// falling back to a deterministic round-robin placement.
+const SPREAD_ATTEMPTS: usize = 200;
+
+/// Stride `insert_cards_between` uses when spacing inserted cards out, so a
+/// later insertion into the same gap can usually reuse the slack instead of
+/// forcing a full renumber.
+const INSERT_BETWEEN_GAP: u32 = 1000;
+
+impl NewCardSortOrder {
+    /// If this is `Random`/`Spread` with no seed, draws a fresh one so the
+    /// caller can report and later replay it. Returns the (possibly
+    /// unchanged) order, and the seed that will be used, if any.
+    fn resolve_random_seed(self) -> (Self, Option<u64>) {
+        match self {
+            NewCardSortOrder::Random { seed: Some(seed) } => {
+                (NewCardSortOrder::Random { seed: Some(seed) }, Some(seed))
+            }
+            NewCardSortOrder::Random { seed: None } => {
+                let seed = rand::random();
+                (NewCardSortOrder::Random { seed: Some(seed) }, Some(seed))
+            }
+            NewCardSortOrder::Spread { min_gap, seed: Some(seed) } => (
+                NewCardSortOrder::Spread { min_gap, seed: Some(seed) },
+                Some(seed),
+            ),
+            NewCardSortOrder::Spread { min_gap, seed: None } => {
+                let seed = rand::random();
+                (
+                    NewCardSortOrder::Spread { min_gap, seed: Some(seed) },
+                    Some(seed),
+                )
+            }
+            other => (other, None),
+        }
+    }
+}
+
 impl NewCardSorter {
     pub(crate) fn new(
         cards: &[Card],
         starting_from: u32,
         step: u32,
         order: NewCardSortOrder,
+        groups: &HashMap<NoteID, String>,
     ) -> Self {
-        let nids = nids_in_desired_order(cards, order);
+        Self::new_with_seed(cards, starting_from, step, order, groups).0
+    }
 
-        NewCardSorter {
-            position: nids
-                .into_iter()
-                .enumerate()
-                .map(|(i, nid)| (nid, ((i as u32) * step) + starting_from))
-                .collect(),
-        }
+    /// Like `new`, but also returns the seed used for a `Random`/`Spread`
+    /// order - drawn fresh if the caller didn't supply one. `groups` maps
+    /// each note id to the key `Spread` uses to keep related notes apart;
+    /// it's ignored by every other order.
+    pub(crate) fn new_with_seed(
+        cards: &[Card],
+        starting_from: u32,
+        step: u32,
+        order: NewCardSortOrder,
+        groups: &HashMap<NoteID, String>,
+    ) -> (Self, Option<u64>) {
+        let (order, seed) = order.resolve_random_seed();
+        let nids = nids_in_desired_order(cards, order, groups);
+
+        (
+            NewCardSorter {
+                position: nids
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, nid)| (nid, ((i as u32) * step) + starting_from))
+                    .collect(),
+            },
+            seed,
+        )
     }
 
     pub(crate) fn position(&self, card: &Card) -> u32 {
@@ -71,7 +145,11 @@ impl NewCardSorter {
     }
 }
 
-fn nids_in_desired_order(cards: &[Card], order: NewCardSortOrder) -> Vec<NoteID> {
+fn nids_in_desired_order(
+    cards: &[Card],
+    order: NewCardSortOrder,
+    groups: &HashMap<NoteID, String>,
+) -> Vec<NoteID> {
     if order == NewCardSortOrder::Preserve {
         nids_in_preserved_order(cards)
     } else {
@@ -81,8 +159,22 @@ fn nids_in_desired_order(cards: &[Card], order: NewCardSortOrder) -> Vec<NoteID>
             NewCardSortOrder::NoteId => {
                 nids.sort_unstable();
             }
-            NewCardSortOrder::Random => {
-                nids.shuffle(&mut rand::thread_rng());
+            NewCardSortOrder::Random { seed } => {
+                // `new`/`new_with_seed` always resolve the seed via
+                // `resolve_random_seed` before we get here; entropy is only
+                // a fallback for a direct call with no seed
+                let mut rng = match seed {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_entropy(),
+                };
+                nids.shuffle(&mut rng);
+            }
+            NewCardSortOrder::Spread { min_gap, seed } => {
+                let mut rng = match seed {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_entropy(),
+                };
+                nids = spread_shuffle(nids, min_gap, groups, &mut rng);
             }
             NewCardSortOrder::Preserve => unreachable!(),
         }
@@ -90,6 +182,73 @@ fn nids_in_desired_order(cards: &[Card], order: NewCardSortOrder) -> Vec<NoteID>
     }
 }
 
+/// Produces an ordering of `nids` in which no two notes sharing a group key
+/// land within `min_gap` positions of each other, if one can be found within
+/// `SPREAD_ATTEMPTS` random tries; otherwise falls back to a deterministic
+/// round-robin placement across groups.
+fn spread_shuffle(
+    mut nids: Vec<NoteID>,
+    min_gap: u32,
+    groups: &HashMap<NoteID, String>,
+    rng: &mut StdRng,
+) -> Vec<NoteID> {
+    for _ in 0..SPREAD_ATTEMPTS {
+        nids.shuffle(rng);
+        if satisfies_min_gap(&nids, min_gap, groups) {
+            return nids;
+        }
+    }
+    round_robin_by_group(nids, groups)
+}
+
+/// Checks whether every pair of same-group notes in `order` is more than
+/// `min_gap` positions apart (at least `min_gap` other notes between them),
+/// bailing out as soon as a violation is found.
+fn satisfies_min_gap(order: &[NoteID], min_gap: u32, groups: &HashMap<NoteID, String>) -> bool {
+    let min_gap = min_gap as usize;
+    let mut last_seen_at: HashMap<&str, usize> = HashMap::new();
+    for (i, nid) in order.iter().enumerate() {
+        let group = groups.get(nid).map(String::as_str).unwrap_or_default();
+        if let Some(&previous) = last_seen_at.get(group) {
+            if i - previous <= min_gap {
+                return false;
+            }
+        }
+        last_seen_at.insert(group, i);
+    }
+    true
+}
+
+/// Buckets `nids` by group, then deals them out round-robin across the
+/// buckets, so notes sharing a group end up maximally separated.
+fn round_robin_by_group(nids: Vec<NoteID>, groups: &HashMap<NoteID, String>) -> Vec<NoteID> {
+    let mut buckets: Vec<VecDeque<NoteID>> = Vec::new();
+    let mut bucket_of_group: HashMap<&str, usize> = HashMap::new();
+    for nid in &nids {
+        let group = groups.get(nid).map(String::as_str).unwrap_or_default();
+        let idx = *bucket_of_group.entry(group).or_insert_with(|| {
+            buckets.push(VecDeque::new());
+            buckets.len() - 1
+        });
+        buckets[idx].push_back(*nid);
+    }
+
+    let mut out = Vec::with_capacity(nids.len());
+    loop {
+        let mut took_any = false;
+        for bucket in &mut buckets {
+            if let Some(nid) = bucket.pop_front() {
+                out.push(nid);
+                took_any = true;
+            }
+        }
+        if !took_any {
+            break;
+        }
+    }
+    out
+}
+
 fn nids_in_preserved_order(cards: &[Card]) -> Vec<NoteID> {
     let mut seen = HashSet::new();
     cards
@@ -125,6 +284,11 @@ impl Collection {
         })
     }
 
+    /// Sorts `cids`, returning the number of cards whose position changed
+    /// and, for a `Random`/`Spread` order, the seed that was used - drawn
+    /// fresh if `order` didn't request a specific one - so a "spread new
+    /// cards" operation can be logged and replayed exactly on another
+    /// device or inside a test.
     pub fn sort_cards(
         &mut self,
         cids: &[CardID],
@@ -132,11 +296,15 @@ impl Collection {
         step: u32,
         order: NewCardSortOrder,
         shift: bool,
-    ) -> Result<OpOutput<usize>> {
+    ) -> Result<OpOutput<SortCardsOut>> {
         let usn = self.usn()?;
-        self.transact(Op::SortCards, |col| {
+        let output = self.transact(Op::SortCards, |col| {
             col.sort_cards_inner(cids, starting_from, step, order, shift, usn)
-        })
+        })?;
+        if let Some(seed) = output.output.seed {
+            tracing::debug!(changed = output.output.changed, seed, "sorted cards");
+        }
+        Ok(output)
     }
 
     fn sort_cards_inner(
@@ -147,37 +315,64 @@ impl Collection {
         order: NewCardSortOrder,
         shift: bool,
         usn: Usn,
-    ) -> Result<usize> {
+    ) -> Result<SortCardsOut> {
         if shift {
             self.shift_existing_cards(starting_from, step * cids.len() as u32, usn)?;
         }
         self.storage.set_search_table_to_card_ids(cids, true)?;
         let cards = self.storage.all_searched_cards_in_search_order()?;
-        let sorter = NewCardSorter::new(&cards, starting_from, step, order);
-        let mut count = 0;
+        let groups = if matches!(order, NewCardSortOrder::Spread { .. }) {
+            self.note_spread_groups(&cards)?
+        } else {
+            HashMap::new()
+        };
+        let (sorter, seed) =
+            NewCardSorter::new_with_seed(&cards, starting_from, step, order, &groups);
+        let mut changed = 0;
         for mut card in cards {
             let original = card.clone();
             if card.set_new_position(sorter.position(&card)) {
-                count += 1;
+                changed += 1;
                 self.update_card_inner(&mut card, original, usn)?;
             }
         }
         self.storage.clear_searched_cards_table()?;
-        Ok(count)
+        Ok(SortCardsOut { changed, seed })
     }
 
     /// This creates a transaction - we probably want to split it out
     /// in the future if calling it as part of a deck options update.
-    pub fn sort_deck(&mut self, deck: DeckID, random: bool) -> Result<OpOutput<usize>> {
+    pub fn sort_deck(&mut self, deck: DeckID, random: bool) -> Result<OpOutput<SortCardsOut>> {
         let cids = self.search_cards(&format!("did:{} is:new", deck), SortMode::NoOrder)?;
         let order = if random {
-            NewCardSortOrder::Random
+            NewCardSortOrder::Random { seed: None }
         } else {
             NewCardSortOrder::NoteId
         };
         self.sort_cards(&cids, 1, 1, order, false)
     }
 
+    /// Builds the group key `NewCardSortOrder::Spread` uses to keep related
+    /// notes apart: the note's first non-empty tag if it has one, else its
+    /// notetype id, else (for a note we somehow can't look up) a key unique
+    /// to that note, so it's simply left out of any grouping.
+    fn note_spread_groups(&mut self, cards: &[Card]) -> Result<HashMap<NoteID, String>> {
+        let mut groups = HashMap::new();
+        for nid in cards.iter().map(|c| c.note_id).collect::<HashSet<_>>() {
+            let key = match self.storage.get_note(nid)? {
+                Some(note) => note
+                    .tags
+                    .iter()
+                    .find(|tag| !tag.is_empty())
+                    .cloned()
+                    .unwrap_or_else(|| format!("notetype:{}", note.notetype_id)),
+                None => format!("__solo_{}", nid.0),
+            };
+            groups.insert(nid, key);
+        }
+        Ok(groups)
+    }
+
     fn shift_existing_cards(&mut self, start: u32, by: u32, usn: Usn) -> Result<()> {
         self.storage.search_cards_at_or_above_position(start)?;
         for mut card in self.storage.all_searched_cards()? {
@@ -188,6 +383,106 @@ impl Collection {
         self.storage.clear_searched_cards_table()?;
         Ok(())
     }
+
+    /// Slots `cids` into the open position interval between `after` and
+    /// `before`, spreading them out evenly so later insertions into the same
+    /// gap can reuse the slack without touching any other card. An omitted
+    /// `after` means position 0; an omitted `before` leaves the interval open
+    /// above `after`, and the cards are then placed `INSERT_BETWEEN_GAP`
+    /// apart without checking other cards' positions. Only falls back to a full
+    /// `shift_existing_cards` renumber when the interval has run out of
+    /// integer room for all of `cids`.
+    pub fn insert_cards_between(
+        &mut self,
+        cids: &[CardID],
+        after: Option<CardID>,
+        before: Option<CardID>,
+    ) -> Result<OpOutput<usize>> {
+        let usn = self.usn()?;
+        self.transact(Op::SortCards, |col| {
+            col.insert_cards_between_inner(cids, after, before, usn)
+        })
+    }
+
+    fn insert_cards_between_inner(
+        &mut self,
+        cids: &[CardID],
+        after: Option<CardID>,
+        before: Option<CardID>,
+        usn: Usn,
+    ) -> Result<usize> {
+        let needed = cids.len() as u32;
+        if needed == 0 {
+            return Ok(0);
+        }
+
+        let lower = match after {
+            Some(cid) => self.due_of(cid)?,
+            None => 0,
+        };
+        let upper = match before {
+            Some(cid) => self.due_of(cid)?,
+            None => lower + needed * INSERT_BETWEEN_GAP + 1,
+        };
+
+        let room = upper.saturating_sub(lower).saturating_sub(1);
+        let step = room / needed;
+
+        if step == 0 {
+            // No integer room left between the neighbors - open up `needed`
+            // slots right after `lower`, then slot the new cards in below.
+            self.shift_existing_cards(lower + 1, needed, usn)?;
+            self.place_cards_in_order(cids, lower + 1, 1, usn)
+        } else {
+            self.place_cards_in_order(cids, lower + step, step, usn)
+        }
+    }
+
+    /// The current `due` position of a single card, looked up via the
+    /// search table like every other single-card storage access in this
+    /// module.
+    fn due_of(&mut self, cid: CardID) -> Result<u32> {
+        self.storage.set_search_table_to_card_ids(&[cid], true)?;
+        let due = self
+            .storage
+            .all_searched_cards()?
+            .first()
+            .map(|c| c.due as u32)
+            .unwrap_or_default();
+        self.storage.clear_searched_cards_table()?;
+        Ok(due)
+    }
+
+    /// Assigns `starting_from`/`step`-spaced positions to `cids` in the
+    /// order given, using `NewCardSorter` as the engine so the spacing
+    /// logic stays in one place.
+    fn place_cards_in_order(
+        &mut self,
+        cids: &[CardID],
+        starting_from: u32,
+        step: u32,
+        usn: Usn,
+    ) -> Result<usize> {
+        self.storage.set_search_table_to_card_ids(cids, true)?;
+        let cards = self.storage.all_searched_cards_in_search_order()?;
+        let sorter = NewCardSorter::new(
+            &cards,
+            starting_from,
+            step,
+            NewCardSortOrder::Preserve,
+            &HashMap::new(),
+        );
+        let mut count = 0;
+        for mut card in cards {
+            let original = card.clone();
+            if card.set_new_position(sorter.position(&card)) {
+                count += 1;
+                self.update_card_inner(&mut card, original, usn)?;
+            }
+        }
+        self.storage.clear_searched_cards_table()?;
+        Ok(count)
+    }
 }
 
 #[cfg(test)]
@@ -204,14 +499,16 @@ mod test {
         c3.id.0 = 1;
         let cards = vec![c1.clone(), c2.clone(), c3.clone()];
 
+        let no_groups = HashMap::new();
+
         // Preserve
-        let sorter = NewCardSorter::new(&cards, 0, 1, NewCardSortOrder::Preserve);
+        let sorter = NewCardSorter::new(&cards, 0, 1, NewCardSortOrder::Preserve, &no_groups);
         assert_eq!(sorter.position(&c1), 0);
         assert_eq!(sorter.position(&c2), 1);
         assert_eq!(sorter.position(&c3), 2);
 
         // NoteID/step/starting
-        let sorter = NewCardSorter::new(&cards, 3, 2, NewCardSortOrder::NoteId);
+        let sorter = NewCardSorter::new(&cards, 3, 2, NewCardSortOrder::NoteId, &no_groups);
         assert_eq!(sorter.position(&c3), 3);
         assert_eq!(sorter.position(&c2), 5);
         assert_eq!(sorter.position(&c1), 7);
@@ -219,7 +516,13 @@ mod test {
         // Random
         let mut c1_positions = HashSet::new();
         for _ in 1..100 {
-            let sorter = NewCardSorter::new(&cards, 0, 1, NewCardSortOrder::Random);
+            let sorter = NewCardSorter::new(
+                &cards,
+                0,
+                1,
+                NewCardSortOrder::Random { seed: None },
+                &no_groups,
+            );
             c1_positions.insert(sorter.position(&c1));
             if c1_positions.len() == cards.len() {
                 return;
@@ -227,4 +530,87 @@ mod test {
         }
         unreachable!("not random");
     }
+
+    #[test]
+    fn random_order_is_reproducible_with_a_seed() {
+        let mut c1 = Card::new(NoteID(6), 0, DeckID(0), 0);
+        c1.id.0 = 2;
+        let mut c2 = Card::new(NoteID(5), 0, DeckID(0), 0);
+        c2.id.0 = 3;
+        let mut c3 = Card::new(NoteID(4), 0, DeckID(0), 0);
+        c3.id.0 = 1;
+        let cards = vec![c1.clone(), c2.clone(), c3.clone()];
+
+        let no_groups = HashMap::new();
+        let (sorter1, seed1) = NewCardSorter::new_with_seed(
+            &cards,
+            0,
+            1,
+            NewCardSortOrder::Random { seed: None },
+            &no_groups,
+        );
+        let seed1 = seed1.expect("a fresh seed is drawn when none is supplied");
+
+        let (sorter2, seed2) = NewCardSorter::new_with_seed(
+            &cards,
+            0,
+            1,
+            NewCardSortOrder::Random { seed: Some(seed1) },
+            &no_groups,
+        );
+        assert_eq!(seed2, Some(seed1));
+        assert_eq!(sorter1.position(&c1), sorter2.position(&c1));
+        assert_eq!(sorter1.position(&c2), sorter2.position(&c2));
+        assert_eq!(sorter1.position(&c3), sorter2.position(&c3));
+    }
+
+    #[test]
+    fn spread_order_keeps_same_group_notes_apart() {
+        let mut c1 = Card::new(NoteID(1), 0, DeckID(0), 0);
+        c1.id.0 = 1;
+        let mut c2 = Card::new(NoteID(2), 0, DeckID(0), 0);
+        c2.id.0 = 2;
+        let mut c3 = Card::new(NoteID(3), 0, DeckID(0), 0);
+        c3.id.0 = 3;
+        let mut c4 = Card::new(NoteID(4), 0, DeckID(0), 0);
+        c4.id.0 = 4;
+        let cards = vec![c1.clone(), c2.clone(), c3.clone(), c4.clone()];
+
+        // c1/c2 share a group, c3/c4 share a different group
+        let groups: HashMap<NoteID, String> = vec![
+            (NoteID(1), "a".to_string()),
+            (NoteID(2), "a".to_string()),
+            (NoteID(3), "b".to_string()),
+            (NoteID(4), "b".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        let sorter = NewCardSorter::new(
+            &cards,
+            0,
+            1,
+            NewCardSortOrder::Spread {
+                min_gap: 1,
+                seed: Some(1),
+            },
+            &groups,
+        );
+        let positions = [
+            sorter.position(&c1),
+            sorter.position(&c2),
+            sorter.position(&c3),
+            sorter.position(&c4),
+        ];
+        assert_ne!(
+            (positions[0] as i64 - positions[1] as i64).abs(),
+            1,
+            "c1/c2 share a group and should not be adjacent"
+        );
+        assert_ne!(
+            (positions[2] as i64 - positions[3] as i64).abs(),
+            1,
+            "c3/c4 share a group and should not be adjacent"
+        );
+    }
 }
@@ -1,7 +1,10 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use std::{collections::HashSet, iter::Peekable};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::Peekable,
+};
 
 use unicase::UniCase;
 
@@ -11,12 +14,42 @@ use crate::{backend_proto::TagTreeNode, prelude::*};
 impl Collection {
     pub fn tag_tree(&mut self) -> Result<TagTreeNode> {
         let tags = self.storage.all_tags()?;
-        let tree = tags_to_tree(tags);
+        // counts keyed by native (\x1f-separated) tag name, covering only notes
+        // tagged with that exact tag, not its descendants; case-folded since the
+        // tree itself merges tags that only differ by case
+        let counts: HashMap<UniCase<String>, u32> = self
+            .storage
+            .tag_note_counts()?
+            .into_iter()
+            .map(|(name, count)| (UniCase::new(name), count))
+            .collect();
+        let mut tree = tags_to_tree(tags);
+        add_counts(&mut tree, "", &counts);
 
         Ok(tree)
     }
 }
 
+/// Populate `count` (notes directly tagged) and `subtree_count` (that count
+/// plus every descendant's) on every node, in a single post-order pass so
+/// each parent's aggregate is ready by the time it sums its children.
+/// Returns the node's subtree_count so the caller can fold it into its own.
+fn add_counts(node: &mut TagTreeNode, path: &str, counts: &HashMap<UniCase<String>, u32>) -> u32 {
+    let mut descendants = 0;
+    for child in &mut node.children {
+        let child_path = if path.is_empty() {
+            child.name.clone()
+        } else {
+            format!("{}\x1f{}", path, child.name)
+        };
+        descendants += add_counts(child, &child_path, counts);
+    }
+    let direct = counts.get(&UniCase::new(path.to_string())).copied().unwrap_or(0);
+    node.count = direct;
+    node.subtree_count = direct + descendants;
+    node.subtree_count
+}
+
 /// Append any missing parents. Caller must sort afterwards.
 fn add_missing_parents(tags: &mut Vec<Tag>) {
     let mut all_names: HashSet<UniCase<&str>> = HashSet::new();
@@ -100,18 +133,21 @@ mod test {
     use super::*;
     use crate::collection::open_test_collection;
 
-    fn node(name: &str, level: u32, children: Vec<TagTreeNode>) -> TagTreeNode {
+    fn node(name: &str, level: u32, count: u32, children: Vec<TagTreeNode>) -> TagTreeNode {
+        let subtree_count = count + children.iter().map(|c| c.subtree_count).sum::<u32>();
         TagTreeNode {
             name: name.into(),
             level,
             children,
+            count,
+            subtree_count,
 
             ..Default::default()
         }
     }
 
-    fn leaf(name: &str, level: u32) -> TagTreeNode {
-        node(name, level, vec![])
+    fn leaf(name: &str, level: u32, count: u32) -> TagTreeNode {
+        node(name, level, count, vec![])
     }
 
     #[test]
@@ -123,22 +159,30 @@ mod test {
         note.tags.push("foo::bar::b".into());
         col.add_note(&mut note, DeckID(1))?;
 
-        // missing parents are added
+        // missing parents are added; counts are only direct on the exact tags
+        // present on the note, rolled up through the synthesized parents
         assert_eq!(
             col.tag_tree()?,
             node(
                 "",
                 0,
+                0,
                 vec![node(
                     "foo",
                     1,
-                    vec![node("bar", 2, vec![leaf("a", 3), leaf("b", 3)])]
+                    0,
+                    vec![node(
+                        "bar",
+                        2,
+                        0,
+                        vec![leaf("a", 3, 1), leaf("b", 3, 1)]
+                    )]
                 )]
             )
         );
 
         // differing case should result in only one parent case being added -
-        // the first one
+        // the first one; counts still match up via case folding
         col.storage.clear_all_tags()?;
         note.tags[0] = "foo::BAR::a".into();
         note.tags[1] = "FOO::bar::b".into();
@@ -148,10 +192,17 @@ mod test {
             node(
                 "",
                 0,
+                0,
                 vec![node(
                     "foo",
                     1,
-                    vec![node("BAR", 2, vec![leaf("a", 3), leaf("b", 3)])]
+                    0,
+                    vec![node(
+                        "BAR",
+                        2,
+                        0,
+                        vec![leaf("a", 3, 1), leaf("b", 3, 1)]
+                    )]
                 )]
             )
         );
@@ -166,10 +217,17 @@ mod test {
             node(
                 "",
                 0,
+                0,
                 vec![node(
                     "foo",
                     1,
-                    vec![node("bar", 2, vec![node("baz", 3, vec![leaf("quux", 4)])])]
+                    0,
+                    vec![node(
+                        "bar",
+                        2,
+                        0,
+                        vec![node("baz", 3, 1, vec![leaf("quux", 4, 1)])]
+                    )]
                 )]
             )
         );
@@ -186,7 +244,8 @@ mod test {
             node(
                 "",
                 0,
-                vec![node("one", 1, vec![leaf("two", 2)]), leaf("one1", 1)]
+                0,
+                vec![node("one", 1, 1, vec![leaf("two", 2, 1)]), leaf("one1", 1, 1)]
             )
         );
 
@@ -2,7 +2,7 @@
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
 use super::Tag;
-use crate::prelude::*;
+use crate::{prelude::*, types::Usn};
 
 #[derive(Debug)]
 pub(crate) enum UndoableTagChange {
@@ -14,20 +14,36 @@ impl Collection {
     pub(crate) fn undo_tag_change(&mut self, change: UndoableTagChange) -> Result<()> {
         match change {
             UndoableTagChange::Added(tag) => self.remove_single_tag_undoable(*tag),
-            UndoableTagChange::Removed(tag) => self.register_tag_undoable(&tag),
+            UndoableTagChange::Removed(tag) => {
+                // restore with the tag's pre-removal usn, not a freshly minted one
+                let usn = tag.usn;
+                self.register_tag_undoable(&tag, usn)
+            }
         }
     }
+
     /// Adds an already-validated tag to the tag list, saving an undo entry.
-    /// Caller is responsible for setting usn.
-    pub(super) fn register_tag_undoable(&mut self, tag: &Tag) -> Result<()> {
+    /// Clears any outstanding grave for the tag, so a tag that is removed and
+    /// re-added before the next sync doesn't get deleted on peers. Caller is
+    /// responsible for choosing `usn`: the collection's current usn for a
+    /// local change, or the usn supplied by the remote side when applying a
+    /// sync.
+    pub(super) fn register_tag_undoable(&mut self, tag: &Tag, usn: Usn) -> Result<()> {
+        let mut tag = tag.clone();
+        tag.usn = usn;
         self.save_undo(UndoableTagChange::Added(Box::new(tag.clone())));
+        self.storage.clear_tag_grave(&tag.name)?;
         self.storage.register_tag(&tag)
     }
 
-    /// Remove a single tag from the tag list, saving an undo entry. Does not alter notes.
-    /// FIXME: caller will need to update usn when we make tags incrementally syncable.
+    /// Remove a single tag from the tag list, saving an undo entry. Does not
+    /// alter notes. Rather than a hard delete, records a grave keyed by tag
+    /// name and deletion usn, so the removal is exchanged as a delta on the
+    /// next sync instead of silently diverging between devices.
     pub(super) fn remove_single_tag_undoable(&mut self, tag: Tag) -> Result<()> {
+        let removal_usn = self.usn()?;
         self.storage.remove_single_tag(&tag.name)?;
+        self.storage.add_tag_grave(&tag.name, removal_usn)?;
         self.save_undo(UndoableTagChange::Removed(Box::new(tag)));
         Ok(())
     }